@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+// in the private_dir
+pub const ACL_FILE: &str = ".acl";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+#[derive(Debug)]
+enum Subject {
+    // anyone, logged in or not
+    All,
+    // any logged in user
+    Users,
+    Group(String),
+    User(String),
+}
+
+#[derive(Debug)]
+struct Rule {
+    prefix: String,
+    subject: Subject,
+    permissions: Vec<Permission>,
+}
+
+/// Path prefixes mapped to who may read, write or delete below them, parsed
+/// from lines like
+///
+/// ```text
+/// group release = releasebot, alice
+/// /                  users      rwd
+/// /content/releases  all        r
+/// /content/releases  @release   rw
+/// ```
+///
+/// The longest prefix with rules decides, paths without any are refused.
+#[derive(Debug, Default)]
+pub struct Acl {
+    groups: HashMap<String, Vec<String>>,
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn parse(acl_str: &str) -> Result<Self, String> {
+        let mut acl = Acl::default();
+        for (index, line) in acl_str.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") || line.starts_with('#') {
+                continue;
+            }
+            if let Some(group) = line.strip_prefix("group ") {
+                let (name, members) = group.split_once('=')
+                    .ok_or_else(|| format!("group without '=' in line {}", index + 1))?;
+                let members = members.split(',').map(|member| member.trim().to_owned()).filter(|member| !member.is_empty());
+                acl.groups.entry(name.trim().to_owned()).or_default().extend(members);
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (prefix, subject, permissions_str) = match fields[..] {
+                [prefix, subject, permissions] => (prefix, subject, permissions),
+                _ => return Err(format!("expected '<path> <who> <rwd>' in line {}", index + 1)),
+            };
+            let prefix = normalize(prefix)
+                .filter(|_| prefix.starts_with('/'))
+                .ok_or_else(|| format!("invalid path '{}' in line {}", prefix, index + 1))?;
+            let subject = match subject {
+                "all" => Subject::All,
+                "users" => Subject::Users,
+                group if group.starts_with('@') => Subject::Group(group[1..].to_owned()),
+                user => Subject::User(user.to_owned()),
+            };
+            let mut permissions = Vec::new();
+            for char in permissions_str.chars() {
+                match char {
+                    'r' => permissions.push(Permission::Read),
+                    'w' => permissions.push(Permission::Write),
+                    'd' => permissions.push(Permission::Delete),
+                    '-' => {},
+                    other => return Err(format!("unknown permission '{}' in line {}", other, index + 1)),
+                }
+            }
+            acl.rules.push(Rule { prefix, subject, permissions });
+        }
+        Ok(acl)
+    }
+
+    pub fn permits(&self, user: Option<&str>, path: &str, permission: Permission) -> bool {
+        // a path leaving ./public is never allowed
+        let path = match normalize(path) {
+            Some(val) => val,
+            None => return false,
+        };
+        let longest = self.rules.iter()
+            .filter(|rule| is_below(&path, &rule.prefix))
+            .map(|rule| rule.prefix.len())
+            .max();
+        let longest = match longest {
+            Some(val) => val,
+            None => return false,
+        };
+        self.rules.iter()
+            .filter(|rule| rule.prefix.len() == longest && is_below(&path, &rule.prefix))
+            .filter(|rule| rule.permissions.contains(&permission))
+            .any(|rule| match (&rule.subject, user) {
+                (Subject::All, _) => true,
+                (Subject::Users, Some(_)) => true,
+                (Subject::User(name), Some(user)) => name == user,
+                (Subject::Group(group), Some(user)) => self.groups.get(group)
+                    .is_some_and(|members| members.iter().any(|member| member == user)),
+                (_, None) => false,
+            })
+    }
+}
+
+/// What `user` may do at `path` under `acl`. Without one everything but
+/// deleting is allowed, deletes always need a rule granting `d`.
+pub fn permits(acl: Option<&Acl>, user: Option<&str>, path: &str, permission: Permission) -> bool {
+    match acl {
+        Some(acl) => acl.permits(user, path, permission),
+        None => permission != Permission::Delete,
+    }
+}
+
+pub fn is_below(path: &str, prefix: &str) -> bool {
+    prefix == "/" || path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Resolves `.` and `..` segments and repeated slashes the way the file
+/// system will, None when the path climbs above the root.
+pub fn normalize(path: &str) -> Option<String> {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop()?;
+            },
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+#[derive(Debug, Default)]
+struct AclState {
+    version: Option<(SystemTime, u64)>,
+    acl: Option<Arc<Acl>>,
+}
+
+/// The ACL file, parsed again when it changes. Without the file, or without
+/// rules in it, access is decided by authentication alone.
+#[derive(Debug)]
+pub struct AclStore {
+    path: String,
+    state: Mutex<AclState>,
+}
+
+impl AclStore {
+    pub fn new(path: &str) -> Self {
+        AclStore {
+            path: path.to_owned(),
+            state: Mutex::new(AclState::default()),
+        }
+    }
+
+    /// An error means the file is there but broken, nobody should get in then.
+    pub fn current(&self) -> Result<Option<Arc<Acl>>, String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let version = match fs::metadata(&self.path).and_then(|metadata| Ok((metadata.modified()?, metadata.len()))) {
+            Ok(val) => val,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                *state = AclState::default();
+                return Ok(None);
+            },
+            Err(e) => return Err(format!("{}: \"{}\"", e, self.path)),
+        };
+        if state.version != Some(version) {
+            let acl = fs::read_to_string(&self.path)
+                .map_err(|e| format!("{}: \"{}\"", e, self.path))
+                .and_then(|acl_str| Acl::parse(&acl_str).map_err(|e| format!("{}: \"{}\"", e, self.path)));
+            let acl = match acl {
+                Ok(val) => val,
+                Err(e) => {
+                    *state = AclState::default();
+                    return Err(e);
+                }
+            };
+            state.acl = if acl.rules.is_empty() { None } else { Some(Arc::new(acl)) };
+            state.version = Some(version);
+        }
+        Ok(state.acl.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize, Acl, Permission};
+
+    #[test]
+    fn releases_world_readable() {
+        let acl = Acl::parse("
+            group release = releasebot
+            /                  users     rwd
+            /content/releases  all       r
+            /content/releases  @release  rw
+        ").unwrap();
+        assert!(acl.permits(None, "/content/releases/v1.tar.gz", Permission::Read));
+        assert!(!acl.permits(None, "/content/releases", Permission::Write));
+        assert!(!acl.permits(Some("alice"), "/content/releases/v2.tar.gz", Permission::Write));
+        assert!(!acl.permits(Some("alice"), "/content/releases/v1.tar.gz", Permission::Delete));
+        assert!(acl.permits(Some("releasebot"), "/content/releases", Permission::Write));
+        assert!(acl.permits(Some("alice"), "/content/releases2", Permission::Write));
+        assert!(!acl.permits(None, "/content/upload", Permission::Read));
+        assert!(!acl.permits(Some("alice"), "/content/upload/../releases/x", Permission::Write));
+        assert!(!acl.permits(Some("alice"), "/../private/.htpasswd", Permission::Read));
+    }
+
+    #[test]
+    fn normalize_path() {
+        assert_eq!(normalize("//content/./upload/../releases?x=1").as_deref(), Some("/content/releases"));
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/content/../.."), None);
+    }
+}
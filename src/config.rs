@@ -0,0 +1,829 @@
+use std::{collections::{BTreeMap, HashMap}, env, ffi::OsString, fmt, fs, net::IpAddr, path::Path, sync::Arc};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::server::{AuthScheme, IoMode, Listener, Mount, ServerConfig};
+
+// like every default path relative to the current directory
+pub const CONFIG_PATH: &str = "./private/config.toml";
+// `name = value` lines with `//` comments, read until it's migrated
+pub const LEGACY_CONFIG_PATH: &str = "./private/.config";
+// followed by a key in capitals, HELLO_SERVER_FILE_SIZE_LIMIT sets file_size_limit
+const ENV_PREFIX: &str = "HELLO_SERVER_";
+
+// every key of ConfigFile in its order
+const KEYS: [&str; 26] = [
+    "auth", "io", "threads", "max_threads", "thread_stack_size", "bulk_threads", "bulk_max_threads", "bulk_threshold",
+    "port", "bind", "listen", "root", "mounts", "private_dir", "tls_cert", "tls_key", "tls_client_ca", "https_redirect_port", "plaintext_passwords",
+    "realm", "forward_auth_url", "forward_auth_user_header", "buf_string_limit", "file_buf_size_limit", "file_size_limit",
+    "vhosts",
+];
+
+// keys a running server can't change, new values wait for a restart; the
+// same for every virtual host
+const RESTART_KEYS: [&str; 13] = [
+    "io", "threads", "max_threads", "thread_stack_size", "bulk_threads", "bulk_max_threads", "port", "bind", "listen",
+    "tls_cert", "tls_key", "tls_client_ca", "https_redirect_port",
+];
+
+/// Where the settings that aren't defaults came from, by key: the config
+/// file's path, the environment variable or the command line flag.
+pub type Sources = HashMap<&'static str, String>;
+
+/// Everything ./private/config.toml may set, unset keys keep the defaults.
+/// Unknown keys and values of the wrong type are errors with the line they
+/// are in.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "auth_schemes")]
+    auth: Option<Vec<AuthScheme>>,
+    #[serde(default, deserialize_with = "io_mode")]
+    io: Option<IoMode>,
+    threads: Option<usize>,
+    max_threads: Option<usize>,
+    thread_stack_size: Option<ByteSize>,
+    bulk_threads: Option<usize>,
+    bulk_max_threads: Option<usize>,
+    bulk_threshold: Option<ByteSize>,
+    port: Option<u16>,
+    bind: Option<IpAddr>,
+    #[serde(default, deserialize_with = "listeners")]
+    listen: Option<Vec<Listener>>,
+    root: Option<String>,
+    #[serde(default, deserialize_with = "mounts")]
+    mounts: Option<Vec<Mount>>,
+    private_dir: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    tls_client_ca: Option<String>,
+    https_redirect_port: Option<u16>,
+    plaintext_passwords: Option<bool>,
+    realm: Option<String>,
+    forward_auth_url: Option<String>,
+    forward_auth_user_header: Option<String>,
+    buf_string_limit: Option<ByteSize>,
+    file_buf_size_limit: Option<ByteSize>,
+    file_size_limit: Option<ByteSize>,
+    // by host name, what's different from the settings above
+    vhosts: Option<BTreeMap<String, ConfigFile>>,
+}
+
+impl ConfigFile {
+    /// Everything but the vhosts, they're built on top of the result by
+    /// `resolve`.
+    pub fn apply(self, s_conf: &mut ServerConfig) {
+        fn set<T>(target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *target = value;
+            }
+        }
+        set(&mut s_conf.auth_schemes, self.auth);
+        set(&mut s_conf.io_mode, self.io);
+        set(&mut s_conf.thread_count, self.threads);
+        set(&mut s_conf.max_threads, self.max_threads);
+        set(&mut s_conf.thread_stack_size, self.thread_stack_size.map(|size| size.0));
+        set(&mut s_conf.bulk_threads, self.bulk_threads);
+        set(&mut s_conf.bulk_max_threads, self.bulk_max_threads);
+        set(&mut s_conf.bulk_threshold, self.bulk_threshold.map(|size| size.0));
+        set(&mut s_conf.port, self.port.map(usize::from));
+        set(&mut s_conf.bind, self.bind);
+        set(&mut s_conf.listen, self.listen);
+        set(&mut s_conf.root, self.root);
+        set(&mut s_conf.mounts, self.mounts);
+        set(&mut s_conf.private_dir, self.private_dir);
+        set(&mut s_conf.tls_cert, self.tls_cert);
+        set(&mut s_conf.tls_key, self.tls_key);
+        set(&mut s_conf.tls_client_ca, self.tls_client_ca);
+        set(&mut s_conf.https_redirect_port, self.https_redirect_port.map(usize::from));
+        set(&mut s_conf.plaintext_passwords, self.plaintext_passwords);
+        set(&mut s_conf.realm, self.realm);
+        set(&mut s_conf.forward_auth_url, self.forward_auth_url);
+        set(&mut s_conf.forward_auth_user_header, self.forward_auth_user_header);
+        set(&mut s_conf.limits.buf_string_limit, self.buf_string_limit.map(|size| size.0));
+        set(&mut s_conf.limits.file_buf_size_limit, self.file_buf_size_limit.map(|size| size.0));
+        set(&mut s_conf.limits.file_size_limit, self.file_size_limit.map(|size| size.0));
+    }
+}
+
+/// A number of bytes, written as a plain number or with a unit like `512K`,
+/// `10MB` or `1GiB`. Units are binary, `1K` is 1024 bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ByteSize(usize);
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ByteSizeVisitor;
+        impl de::Visitor<'_> for ByteSizeVisitor {
+            type Value = ByteSize;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a number of bytes or a size like \"10MB\"")
+            }
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<ByteSize, E> {
+                usize::try_from(value).map(ByteSize).map_err(|_| E::custom("a size can't be negative"))
+            }
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<ByteSize, E> {
+                usize::try_from(value).map(ByteSize).map_err(E::custom)
+            }
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<ByteSize, E> {
+                parse_size(value).map(ByteSize).map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_any(ByteSizeVisitor)
+    }
+}
+
+pub fn parse_size(str: &str) -> Result<usize, String> {
+    let str = str.trim();
+    let digits_end = str.find(|char: char| !char.is_ascii_digit()).unwrap_or(str.len());
+    let number: usize = str[..digits_end].parse().map_err(|_| format!("'{}' isn't a size, use e.g. 4096, 512K, 10MB or 1G", str))?;
+    let multiplier = match str[digits_end..].trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        other => return Err(format!("unknown unit '{}' in size '{}', use K, M or G", other, str)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("size '{}' is too big", str))
+}
+
+/// `auth = "basic"` or `auth = ["session", "basic"]`.
+fn auth_schemes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<AuthScheme>>, D::Error> {
+    struct AuthVisitor;
+    impl<'de> de::Visitor<'de> for AuthVisitor {
+        type Value = Vec<AuthScheme>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a scheme like \"basic\" or a list of them")
+        }
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            AuthScheme::parse_list(value).map_err(E::custom)
+        }
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut names = Vec::new();
+            while let Some(name) = seq.next_element::<String>()? {
+                names.push(name);
+            }
+            AuthScheme::parse_list(&names.join(",")).map_err(de::Error::custom)
+        }
+    }
+    deserializer.deserialize_any(AuthVisitor).map(Some)
+}
+
+/// `listen = "127.0.0.1:8080, [::1]:8080"`, a list of such addresses, or
+/// tables with their own TLS settings or `admin = true`:
+/// `listen = [{ address = "[::]:8443", tls_cert = "...", tls_key = "..." }]`.
+fn listeners<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Listener>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ListenerTable {
+        address: String,
+        tls: Option<bool>,
+        tls_cert: Option<String>,
+        tls_key: Option<String>,
+        admin: Option<bool>,
+    }
+    struct ListenerVisitor;
+    impl<'de> de::Visitor<'de> for ListenerVisitor {
+        type Value = Vec<Listener>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an address like \"[::]:8080\" or a list of addresses and tables")
+        }
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            value.split(',').filter(|address| !address.trim().is_empty()).map(|address| Listener::parse(address).map_err(E::custom)).collect()
+        }
+        fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            let table = ListenerTable::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            let mut listener = Listener::parse(&table.address).map_err(de::Error::custom)?;
+            listener.tls = table.tls.or(listener.tls);
+            listener.tls_cert = table.tls_cert.unwrap_or_default();
+            listener.tls_key = table.tls_key.unwrap_or_default();
+            listener.admin = table.admin.unwrap_or_default();
+            if listener.tls_cert.is_empty() != listener.tls_key.is_empty() {
+                return Err(de::Error::custom(format!("listener {} needs both tls_cert and tls_key", listener.address)));
+            }
+            Ok(vec![listener])
+        }
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut listeners = Vec::new();
+            while let Some(entry) = seq.next_element::<ListenerEntry>()? {
+                listeners.extend(entry.0);
+            }
+            Ok(listeners)
+        }
+    }
+    // one string or table of the list
+    struct ListenerEntry(Vec<Listener>);
+    impl<'de> Deserialize<'de> for ListenerEntry {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(ListenerVisitor).map(ListenerEntry)
+        }
+    }
+    deserializer.deserialize_any(ListenerVisitor).map(Some)
+}
+
+/// `mounts = "/datasets=/mnt/nas/datasets, /scratch=/tmp/scratch"` or a list
+/// of tables: `mounts = [{ prefix = "/datasets", path = "...", read_only = true }]`.
+fn mounts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Mount>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct MountTable {
+        prefix: String,
+        path: String,
+        read_only: Option<bool>,
+        upload: Option<bool>,
+    }
+    fn mount(table: MountTable) -> Result<Mount, String> {
+        let prefix = crate::acl::normalize(&table.prefix)
+            .filter(|prefix| prefix != "/")
+            .ok_or_else(|| format!("mount prefix '{}' isn't a path below /", table.prefix))?;
+        if table.path.is_empty() {
+            return Err(format!("mount {} needs a path", prefix));
+        }
+        Ok(Mount {
+            prefix,
+            path: table.path,
+            read_only: table.read_only.unwrap_or(false),
+            upload: table.upload.unwrap_or(true),
+        })
+    }
+    struct MountVisitor;
+    impl<'de> de::Visitor<'de> for MountVisitor {
+        type Value = Vec<Mount>;
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("\"/prefix=/path\" or a list of mount tables")
+        }
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            value.split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| {
+                    let (prefix, path) = entry.split_once('=').ok_or_else(|| E::custom(format!("'{}' isn't /prefix=/path", entry.trim())))?;
+                    let table = MountTable { prefix: prefix.trim().to_owned(), path: path.trim().to_owned(), read_only: None, upload: None };
+                    mount(table).map_err(E::custom)
+                })
+                .collect()
+        }
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut mounts = Vec::new();
+            while let Some(table) = seq.next_element::<MountTable>()? {
+                mounts.push(mount(table).map_err(de::Error::custom)?);
+            }
+            Ok(mounts)
+        }
+    }
+    deserializer.deserialize_any(MountVisitor).map(Some)
+}
+
+fn io_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<IoMode>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    IoMode::try_from(value.as_str()).map(Some).map_err(de::Error::custom)
+}
+
+pub fn parse(toml_str: &str, path: &str) -> Result<ConfigFile, String> {
+    toml::from_str(toml_str).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Where the config file is read from, and `init` writes it: `--config`,
+/// else `HELLO_SERVER_CONFIG`, else ./private/config.toml.
+pub fn config_path(cli: &Cli) -> String {
+    cli.config_path.clone()
+        .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok())
+        .unwrap_or_else(|| CONFIG_PATH.to_owned())
+}
+
+/// Path and TOML text of the config file, `path` or else
+/// ./private/config.toml, or the old ./private/.config as long as there's no
+/// config.toml. None without either.
+fn read_file(path: Option<&str>) -> Result<Option<(String, String)>, String> {
+    let read = |path: &str| fs::read_to_string(path).map_err(|e| format!("{}: \"{}\"", e, path));
+    if let Some(path) = path {
+        return Ok(Some((path.to_owned(), read(path)?)));
+    }
+    if Path::new(CONFIG_PATH).exists() {
+        return Ok(Some((CONFIG_PATH.to_owned(), read(CONFIG_PATH)?)));
+    }
+    if Path::new(LEGACY_CONFIG_PATH).exists() {
+        println!("{} is in the old format, `hello_server config migrate` turns it into {}", LEGACY_CONFIG_PATH, CONFIG_PATH);
+        return Ok(Some((LEGACY_CONFIG_PATH.to_owned(), migrate(&read(LEGACY_CONFIG_PATH)?)?)));
+    }
+    Ok(None)
+}
+
+/// The effective server config: defaults, overridden by the config file,
+/// then by `HELLO_SERVER_*` variables in `vars`, then by the command line,
+/// for the virtual hosts too. `HELLO_SERVER_CONFIG` names the file when `--config` doesn't.
+pub fn resolve(cli: &Cli, vars: impl Iterator<Item = (OsString, OsString)>) -> Result<(ServerConfig, Sources), String> {
+    let mut s_conf = ServerConfig::new();
+    let mut sources = Sources::new();
+    let mut env_config_path = None;
+    let mut env_settings = Vec::new();
+    for (var, value) in vars {
+        // other variables may be anything, only ours have to be unicode
+        let var = var.to_string_lossy().into_owned();
+        let name = match var.strip_prefix(ENV_PREFIX) {
+            Some(val) => val.to_lowercase(),
+            None => continue,
+        };
+        let value = value.into_string().map_err(|_| format!("environment variable {} isn't valid unicode", var))?;
+        if name == "config" {
+            env_config_path = Some(value);
+            continue;
+        }
+        // a typo would otherwise be ignored without a word
+        let key = KEYS.iter().find(|key| **key == name).ok_or_else(|| format!("unknown environment variable {}", var))?;
+        env_settings.push((var, *key, value));
+    }
+    let mut vhost_files = BTreeMap::new();
+    if let Some((path, toml_str)) = read_file(cli.config_path.as_deref().or(env_config_path.as_deref()))? {
+        let mut config_file = parse(&toml_str, &path)?;
+        vhost_files = config_file.vhosts.take().unwrap_or_default();
+        config_file.apply(&mut s_conf);
+        let table: toml::Table = toml_str.parse().map_err(|e| format!("{}: {}", path, e))?;
+        for key in KEYS.iter().filter(|key| table.contains_key(**key)) {
+            sources.insert(key, path.clone());
+        }
+        let vhost_tables = table.get("vhosts").and_then(toml::Value::as_table).into_iter().flatten();
+        for (name, vhost_table) in vhost_tables {
+            let shared = vhost_table.as_table().and_then(|vhost_table| {
+                vhost_table.keys().find(|key| *key == "vhosts" || RESTART_KEYS.contains(&key.as_str()))
+            });
+            if let Some(key) = shared {
+                return Err(format!("{}: vhosts.\"{}\": {} can't differ between virtual hosts", path, name, key));
+            }
+        }
+    }
+    let overrides = |s_conf: &mut ServerConfig| -> Result<(), String> {
+        for (var, key, value) in &env_settings {
+            set(s_conf, key, value).map_err(|e| format!("{}={}: {}", var, value, e))?;
+        }
+        for (flag, key, value) in &cli.settings {
+            set(s_conf, key, value).map_err(|e| format!("{} {}: {}", flag, value, e))?;
+        }
+        Ok(())
+    };
+    // the command line and environment win over a host's own table as well
+    let file_conf = s_conf.clone();
+    overrides(&mut s_conf)?;
+    for (var, key, _) in &env_settings {
+        sources.insert(key, var.clone());
+    }
+    for (flag, key, _) in &cli.settings {
+        sources.insert(key, format!("command line {}", flag));
+    }
+    for (name, vhost_file) in vhost_files {
+        let mut vhost_conf = file_conf.clone();
+        vhost_conf.vhost = name.to_lowercase();
+        vhost_file.apply(&mut vhost_conf);
+        overrides(&mut vhost_conf)?;
+        s_conf.vhosts.insert(vhost_conf.vhost.clone(), Arc::new(vhost_conf));
+    }
+    Ok((s_conf, sources))
+}
+
+/// Sets `key` from a value given as text, checked the same way as in the
+/// file.
+fn set(s_conf: &mut ServerConfig, key: &str, value: &str) -> Result<(), String> {
+    // "8080" is meant as a number for port but as text for realm,
+    // the first type the key takes wins
+    let mut candidates = Vec::new();
+    if let Ok(number) = value.parse() {
+        candidates.push(toml::Value::Integer(number));
+    }
+    if let Ok(bool) = value.parse() {
+        candidates.push(toml::Value::Boolean(bool));
+    }
+    candidates.push(toml::Value::String(value.to_owned()));
+    let mut first_error = None;
+    for candidate in candidates {
+        let mut table = toml::Table::new();
+        table.insert(key.to_string(), candidate);
+        match toml::Value::Table(table).try_into::<ConfigFile>() {
+            Ok(config_file) => {
+                config_file.apply(s_conf);
+                return Ok(());
+            },
+            Err(e) => {
+                first_error.get_or_insert(e.to_string().trim().to_owned());
+            },
+        }
+    }
+    Err(first_error.unwrap_or_default())
+}
+
+/// The settings as TOML values, by key in the order of KEYS.
+fn values(s_conf: &ServerConfig) -> Vec<(&'static str, toml::Value)> {
+    use toml::Value;
+    let number = |number: usize| Value::Integer(number as i64);
+    let string = |str: &str| Value::String(str.to_owned());
+    let listen = s_conf.listen.iter().map(|listener| {
+        let address = match listener.tls {
+            Some(true) => format!("https://{}", listener.address),
+            Some(false) => format!("http://{}", listener.address),
+            None => listener.address.to_string(),
+        };
+        if listener.tls_cert.is_empty() && !listener.admin {
+            return Value::String(address);
+        }
+        let mut table = toml::Table::new();
+        table.insert("address".to_owned(), Value::String(address));
+        if !listener.tls_cert.is_empty() {
+            table.insert("tls_cert".to_owned(), string(&listener.tls_cert));
+            table.insert("tls_key".to_owned(), string(&listener.tls_key));
+        }
+        if listener.admin {
+            table.insert("admin".to_owned(), Value::Boolean(true));
+        }
+        Value::Table(table)
+    });
+    // what each host has different
+    let mut vhosts = toml::Table::new();
+    let defaults = if s_conf.vhosts.is_empty() {
+        Vec::new()
+    } else {
+        values(&ServerConfig { vhosts: BTreeMap::new(), ..s_conf.clone() })
+    };
+    for (name, vhost_conf) in &s_conf.vhosts {
+        let differences = values(vhost_conf).into_iter()
+            .zip(defaults.iter().cloned())
+            .filter(|((key, value), (_, default))| *key != "vhosts" && value != default)
+            .map(|((key, value), _)| (key.to_owned(), value))
+            .collect();
+        vhosts.insert(name.clone(), Value::Table(differences));
+    }
+    let mounts = s_conf.mounts.iter().map(|mount| {
+        let mut table = toml::Table::new();
+        table.insert("prefix".to_owned(), string(&mount.prefix));
+        table.insert("path".to_owned(), string(&mount.path));
+        table.insert("read_only".to_owned(), Value::Boolean(mount.read_only));
+        table.insert("upload".to_owned(), Value::Boolean(mount.upload));
+        Value::Table(table)
+    });
+    vec![
+        ("auth", Value::Array(s_conf.auth_schemes.iter().map(|scheme| string(scheme.as_str())).collect())),
+        ("io", string(s_conf.io_mode.as_str())),
+        ("threads", number(s_conf.thread_count)),
+        ("max_threads", number(s_conf.max_threads)),
+        ("thread_stack_size", number(s_conf.thread_stack_size)),
+        ("bulk_threads", number(s_conf.bulk_threads)),
+        ("bulk_max_threads", number(s_conf.bulk_max_threads)),
+        ("bulk_threshold", number(s_conf.bulk_threshold)),
+        ("port", number(s_conf.port)),
+        ("bind", string(&s_conf.bind.to_string())),
+        ("listen", Value::Array(listen.collect())),
+        ("root", string(&s_conf.root)),
+        ("mounts", Value::Array(mounts.collect())),
+        ("private_dir", string(&s_conf.private_dir)),
+        ("tls_cert", string(&s_conf.tls_cert)),
+        ("tls_key", string(&s_conf.tls_key)),
+        ("tls_client_ca", string(&s_conf.tls_client_ca)),
+        ("https_redirect_port", number(s_conf.https_redirect_port)),
+        ("plaintext_passwords", Value::Boolean(s_conf.plaintext_passwords)),
+        ("realm", string(&s_conf.realm)),
+        ("forward_auth_url", string(&s_conf.forward_auth_url)),
+        ("forward_auth_user_header", string(&s_conf.forward_auth_user_header)),
+        ("buf_string_limit", number(s_conf.limits.buf_string_limit)),
+        ("file_buf_size_limit", number(s_conf.limits.file_buf_size_limit)),
+        ("file_size_limit", number(s_conf.limits.file_size_limit)),
+        ("vhosts", Value::Table(vhosts)),
+    ]
+}
+
+/// Goes from the `old` settings of a running server to `new` ones, except
+/// for RESTART_KEYS, which keep their old values. Also tells the keys that
+/// changed and the ones that would have but need a restart.
+pub fn reload(old: &ServerConfig, mut new: ServerConfig) -> (ServerConfig, Vec<&'static str>, Vec<&'static str>) {
+    let mut changed = Vec::new();
+    let mut restart = Vec::new();
+    let mut kept = toml::Table::new();
+    for ((key, old_value), (_, new_value)) in values(old).into_iter().zip(values(&new)) {
+        if old_value == new_value {
+            continue;
+        }
+        if RESTART_KEYS.contains(&key) {
+            restart.push(key);
+            kept.insert(key.to_owned(), old_value);
+        } else {
+            changed.push(key);
+        }
+    }
+    let mut vhost_confs: Vec<ServerConfig> = new.vhosts.values().map(|vhost_conf| (**vhost_conf).clone()).collect();
+    // the hosts got theirs from new, which doesn't run yet either
+    let configs = std::iter::once(&mut new).chain(vhost_confs.iter_mut());
+    for s_conf in configs {
+        match toml::Value::Table(kept.clone()).try_into::<ConfigFile>() {
+            Ok(config_file) => config_file.apply(s_conf),
+            Err(e) => println!("ERROR: config reload\n{}", e),
+        }
+    }
+    for vhost_conf in vhost_confs {
+        new.vhosts.insert(vhost_conf.vhost.clone(), Arc::new(vhost_conf));
+    }
+    (new, changed, restart)
+}
+
+/// The effective config as TOML, every line commented with where the
+/// value came from.
+pub fn show(s_conf: &ServerConfig, sources: &Sources) -> String {
+    values(s_conf).into_iter()
+        .map(|(key, value)| {
+            let line = format!("{} = {}", key, value);
+            format!("{:<48} # {}\n", line, sources.get(key).map_or("default", |source| source.as_str()))
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Number,
+    Size,
+    Bool,
+    List,
+}
+
+fn legacy_kind(name: &str) -> Option<Kind> {
+    match name {
+        "auth" => Some(Kind::List),
+        "io" | "bind" | "root" | "tls_cert" | "tls_key" | "tls_client_ca" | "realm" | "forward_auth_url" | "forward_auth_user_header" => Some(Kind::Text),
+        "threads" | "max_threads" | "bulk_threads" | "bulk_max_threads" | "port" | "https_redirect_port" => Some(Kind::Number),
+        "thread_stack_size" | "bulk_threshold" | "buf_string_limit" | "file_buf_size_limit" | "file_size_limit" => Some(Kind::Size),
+        "plaintext_passwords" => Some(Kind::Bool),
+        _ => None,
+    }
+}
+
+/// The old format turned into TOML line by line, comments included, so
+/// errors found in the result point at the same lines.
+pub fn migrate(legacy_str: &str) -> Result<String, String> {
+    let mut toml_str = String::new();
+    for (index, line) in legacy_str.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            toml_str.push('\n');
+            continue;
+        }
+        if let Some(comment) = trimmed.strip_prefix("//") {
+            toml_str.push_str(&format!("#{}\n", comment.trim_end()));
+            continue;
+        }
+        let (name, value) = trimmed.split_once('=')
+            .ok_or_else(|| format!("{}: expected 'name = value' in line {}", LEGACY_CONFIG_PATH, index + 1))?;
+        let name = name.trim().to_lowercase();
+        let value = value.trim();
+        let kind = legacy_kind(&name)
+            .ok_or_else(|| format!("{}: unknown option '{}' in line {}", LEGACY_CONFIG_PATH, name, index + 1))?;
+        let quoted = |str: &str| toml::Value::String(str.to_owned()).to_string();
+        let value = match kind {
+            Kind::Number | Kind::Size if !value.is_empty() && value.chars().all(|char| char.is_ascii_digit()) => value.to_owned(),
+            Kind::Bool if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") => value.to_lowercase(),
+            Kind::List => {
+                let names: Vec<String> = value.split(',').map(str::trim).filter(|name| !name.is_empty()).map(quoted).collect();
+                format!("[{}]", names.join(", "))
+            },
+            // checked when the result is parsed
+            Kind::Text | Kind::Number | Kind::Size | Kind::Bool => quoted(value),
+        };
+        toml_str.push_str(&format!("{} = {}\n", name, value));
+    }
+    Ok(toml_str)
+}
+
+// command line flags and the config keys they set
+const FLAGS: [(&str, &str); 31] = [
+    ("-a", "auth"),
+    ("--auth", "auth"),
+    ("--io", "io"),
+    ("-t", "threads"),
+    ("--threads", "threads"),
+    ("--max-threads", "max_threads"),
+    ("--thread-stack-size", "thread_stack_size"),
+    ("--bulk-threads", "bulk_threads"),
+    ("--bulk-max-threads", "bulk_max_threads"),
+    ("--bulk-threshold", "bulk_threshold"),
+    ("-p", "port"),
+    ("--port", "port"),
+    ("-b", "bind"),
+    ("--bind", "bind"),
+    ("-l", "listen"),
+    ("--listen", "listen"),
+    ("-r", "root"),
+    ("--root", "root"),
+    ("--mounts", "mounts"),
+    ("--private-dir", "private_dir"),
+    ("--tls-cert", "tls_cert"),
+    ("--tls-key", "tls_key"),
+    ("--tls-client-ca", "tls_client_ca"),
+    ("--redirect-port", "https_redirect_port"),
+    ("--plaintext-passwords", "plaintext_passwords"),
+    ("--realm", "realm"),
+    ("--forward-auth-url", "forward_auth_url"),
+    ("--forward-auth-user-header", "forward_auth_user_header"),
+    ("--buf-string-limit", "buf_string_limit"),
+    ("--file-buf-size-limit", "file_buf_size_limit"),
+    ("--file-size-limit", "file_size_limit"),
+];
+
+/// The server's command line. Every config key has a flag, `--name value`
+/// or `--name=value`, checked the same way as in the file and applied on
+/// top of it and the environment.
+#[derive(Debug, Default)]
+pub struct Cli {
+    pub help: bool,
+    pub version: bool,
+    pub config_path: Option<String>,
+    // flag as given, config key, value
+    settings: Vec<(String, &'static str, String)>,
+}
+
+impl Cli {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli::default();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_owned(), Some(value.to_owned())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("{} needs a value", flag));
+            match flag.as_str() {
+                "-h" | "--help" | "help" => cli.help = true,
+                "-V" | "--version" => cli.version = true,
+                "-c" | "--config" => cli.config_path = Some(value()?),
+                flag_str => match FLAGS.iter().find(|(name, _)| *name == flag_str) {
+                    Some((_, key)) => cli.settings.push((flag.clone(), key, value()?)),
+                    None => {
+                        let close = FLAGS.iter()
+                            .find(|(name, _)| name.len() > 2 && (flag_str.starts_with(name) || name.starts_with(flag_str)) && flag_str.len() > 3)
+                            .map_or(String::new(), |(name, _)| format!(", did you mean {}?", name));
+                        return Err(format!("unknown option '{}'{}", flag_str, close));
+                    },
+                },
+            }
+        }
+        Ok(cli)
+    }
+}
+
+/// `hello_server config migrate [--force]` writes ./private/config.toml
+/// from ./private/.config, which is left for comparison.
+/// `hello_server config show [OPTIONS]` prints the settings the server
+/// would start with and where each comes from.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let command = args.next().ok_or("config needs a command: migrate or show")?;
+    match command.as_str() {
+        "show" => {
+            let (s_conf, sources) = resolve(&Cli::parse(args)?, env::vars_os())?;
+            print!("{}", show(&s_conf, &sources));
+        },
+        "migrate" => {
+            let force = match args.next().as_deref() {
+                Some("--force") => true,
+                Some(other) => return Err(format!("unknown config option '{}'", other)),
+                None => false,
+            };
+            if Path::new(CONFIG_PATH).exists() && !force {
+                return Err(format!("{} exists, --force replaces it", CONFIG_PATH));
+            }
+            let legacy_str = fs::read_to_string(LEGACY_CONFIG_PATH).map_err(|e| format!("{}: \"{}\"", e, LEGACY_CONFIG_PATH))?;
+            let toml_str = migrate(&legacy_str)?;
+            // nothing is written that the server wouldn't start with
+            parse(&toml_str, LEGACY_CONFIG_PATH)?;
+            fs::write(CONFIG_PATH, toml_str).map_err(|e| format!("{}: \"{}\"", e, CONFIG_PATH))?;
+            println!("{} written, {} isn't read anymore and can be removed", CONFIG_PATH, LEGACY_CONFIG_PATH);
+        },
+        other => return Err(format!("unknown config command '{}'", other)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+
+    use super::{migrate, parse, parse_size, reload, resolve, show, values, Cli, KEYS};
+    use crate::server::{AuthScheme, ServerConfig};
+
+    #[test]
+    fn validated_toml() {
+        let mut s_conf = ServerConfig::new();
+        parse("auth = [\"session\", \"basic\"]\nport = 8443\nfile_size_limit = \"10MB\"\nbulk_threshold = 4096", "test")
+            .unwrap()
+            .apply(&mut s_conf);
+        assert_eq!(s_conf.auth_schemes, vec![AuthScheme::Session, AuthScheme::Basic]);
+        assert_eq!((s_conf.port, s_conf.limits.file_size_limit, s_conf.bulk_threshold), (8443, 10 * 1024 * 1024, 4096));
+
+        let e = parse("port = 8080\nthreds = 4\n", "test").unwrap_err();
+        assert!(e.contains("line 2") && e.contains("threds"), "{}", e);
+        let e = parse("\nport = 70000\n", "test").unwrap_err();
+        assert!(e.contains("line 2"), "{}", e);
+        let e = parse("auth = \"basic, kerberos\"", "test").unwrap_err();
+        assert!(e.contains("kerberos"), "{}", e);
+        assert!(parse_size("10 furlongs").is_err());
+
+        parse("listen = [\"http://127.0.0.1:9090\", { address = \"[::]:8443\", tls_cert = \"a.pem\", tls_key = \"b.pem\" }]", "test")
+            .unwrap()
+            .apply(&mut s_conf);
+        assert_eq!(s_conf.listen.len(), 2);
+        assert_eq!(s_conf.listen[0].tls_files(&s_conf), None);
+        assert_eq!(s_conf.listen[1].tls_files(&s_conf), Some(("a.pem", "b.pem")));
+
+        parse("mounts = [{ prefix = \"/datasets/\", path = \"/mnt/nas\", read_only = true }, { prefix = \"/datasets/new\", path = \"/srv/new\" }]", "test")
+            .unwrap()
+            .apply(&mut s_conf);
+        assert_eq!(s_conf.mount_for("/datasets/new/x").map(|mount| mount.path.as_str()), Some("/srv/new"));
+        assert!(s_conf.mount_for("/datasets/a").is_some_and(|mount| mount.read_only && mount.prefix == "/datasets"));
+        assert!(s_conf.mount_for("/datasetsx").is_none());
+        assert!(parse("mounts = \"/=/mnt\"", "test").is_err());
+        let e = parse("listen = \"localhost:8080\"", "test").unwrap_err();
+        assert!(e.contains("localhost:8080"), "{}", e);
+    }
+
+    #[test]
+    fn legacy_migration() {
+        let toml_str = migrate("// hello\nauth = basic, digest\nPort = 8080\nbulk_threshold = 1M\nrealm = My Files\n").unwrap();
+        assert_eq!(toml_str, "# hello\nauth = [\"basic\", \"digest\"]\nport = 8080\nbulk_threshold = \"1M\"\nrealm = \"My Files\"\n");
+        let mut s_conf = ServerConfig::new();
+        parse(&toml_str, "test").unwrap().apply(&mut s_conf);
+        assert_eq!(s_conf.bulk_threshold, 1024 * 1024);
+        assert!(migrate("port = 8080\nprot = 8081\n").unwrap_err().contains("line 2"));
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (OsString, OsString)> {
+        vars.iter().map(|(var, value)| (var.into(), value.into())).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn command_line() {
+        let path = std::env::temp_dir().join(format!("hello_server_command_line_{}.toml", std::process::id()));
+        std::fs::write(&path, "max_threads = 8\nport = 8000\n").unwrap();
+        let path_str = path.display().to_string();
+        let args = ["--port", "9000", "--realm=8080", "-a", "session,basic", "--file-size-limit", "2GB", "-c", &path_str];
+        let cli = Cli::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(cli.config_path.as_deref(), Some(path_str.as_str()));
+        let (s_conf, sources) = resolve(&cli, vars(&[("HELLO_SERVER_PORT", "9001"), ("HELLO_SERVER_THREADS", "3"), ("PATH", "/bin")])).unwrap();
+        assert_eq!((s_conf.port, s_conf.realm.as_str(), s_conf.thread_count, s_conf.max_threads), (9000, "8080", 3, 8));
+        assert_eq!(s_conf.limits.file_size_limit, 2 * 1024 * 1024 * 1024);
+        assert_eq!(s_conf.auth_schemes, vec![AuthScheme::Session, AuthScheme::Basic]);
+        assert_eq!((sources["port"].as_str(), sources["threads"].as_str()), ("command line --port", "HELLO_SERVER_THREADS"));
+        assert_eq!(sources["max_threads"], path_str);
+        let e = resolve(&cli, vars(&[("HELLO_SERVER_PROT", "1")])).unwrap_err();
+        assert!(e.contains("HELLO_SERVER_PROT"), "{}", e);
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            let invalid = || OsString::from_vec(vec![0xff]);
+            let e = resolve(&cli, [(invalid(), "1".into()), ("HELLO_SERVER_REALM".into(), invalid())].into_iter()).unwrap_err();
+            assert!(e.contains("HELLO_SERVER_REALM isn't valid unicode"), "{}", e);
+        }
+
+        // what config show prints is a config file giving the same settings
+        let keys: Vec<&str> = values(&s_conf).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, KEYS);
+        let mut shown = ServerConfig::new();
+        parse(&show(&s_conf, &sources), "show").unwrap().apply(&mut shown);
+        assert_eq!(values(&shown), values(&s_conf));
+
+        let e = Cli::parse(["--ports", "9000"].iter().map(|arg| arg.to_string())).unwrap_err();
+        assert!(e.contains("did you mean --port"), "{}", e);
+        let cli = Cli::parse(["-p", "http", "-c", &path_str].iter().map(|arg| arg.to_string())).unwrap();
+        assert!(resolve(&cli, std::iter::empty()).unwrap_err().starts_with("-p http: "));
+        std::fs::remove_file(&path).unwrap();
+        assert!(Cli::parse(["--threads"].iter().map(|arg| arg.to_string())).is_err());
+    }
+
+    #[test]
+    fn reload_keeps_restart_settings() {
+        let old = ServerConfig::new();
+        let mut new = ServerConfig::new();
+        parse("port = 9000\nrealm = \"files\"\nauth = \"basic\"\nfile_size_limit = \"5MB\"", "test").unwrap().apply(&mut new);
+        let (reloaded, changed, restart) = reload(&old, new);
+        assert_eq!(changed, ["auth", "realm", "file_size_limit"]);
+        assert_eq!(restart, ["port"]);
+        assert_eq!((reloaded.port, reloaded.realm.as_str()), (8080, "files"));
+        assert_eq!(reloaded.auth_schemes, vec![AuthScheme::Basic]);
+    }
+
+    #[test]
+    fn vhosts() {
+        let path = std::env::temp_dir().join(format!("hello_server_vhosts_{}.toml", std::process::id()));
+        let config = |toml_str: &str| {
+            std::fs::write(&path, toml_str).unwrap();
+            let cli = Cli::parse(["-c".to_string(), path.display().to_string()].into_iter()).unwrap();
+            resolve(&cli, vars(&[("HELLO_SERVER_REALM", "env"), ("HELLO_SERVER_AUTH", "basic")]))
+        };
+        let toml_str = "root = \"./public\"\n\
+            [vhosts.\"Files.TeamA.lan\"]\nroot = \"/srv/team-a\"\nprivate_dir = \"/srv/team-a-private\"\nauth = \"session\"\n\
+            [vhosts.\"files.teamb.lan\"]\n";
+        let (s_conf, sources) = config(toml_str).unwrap();
+        let team_a = &s_conf.vhosts["files.teama.lan"];
+        assert_eq!((team_a.root.as_str(), team_a.private_dir.as_str(), team_a.vhost.as_str()), ("/srv/team-a", "/srv/team-a-private", "files.teama.lan"));
+        // the environment wins over the host's table
+        assert_eq!((team_a.auth_schemes.as_slice(), team_a.realm.as_str()), ([AuthScheme::Basic].as_slice(), "env"));
+        assert_eq!(s_conf.vhosts["files.teamb.lan"].root, "./public");
+        assert!(team_a.vhosts.is_empty());
+        let shown = show(&s_conf, &sources);
+        assert!(shown.contains("vhosts = { \"files.teama.lan\" = { "), "{}", shown);
+        parse(&shown, "show").unwrap();
+        let e = config("[vhosts.\"files.teama.lan\"]\nport = 8443\n").unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(e.ends_with("vhosts.\"files.teama.lan\": port can't differ between virtual hosts"), "{}", e);
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::server::{Auth, AuthError};
+
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+// every 401 hands out a nonce, don't let unauthenticated clients grow the table forever
+const MAX_NONCES: usize = 10_000;
+// how far below the highest nc a nonce count may still arrive, browsers
+// send parallel requests out of order
+const NC_WINDOW: u32 = 64;
+
+static NONCES: OnceLock<Mutex<HashMap<String, Nonce>>> = OnceLock::new();
+
+#[derive(Debug)]
+struct Nonce {
+    issued: Instant,
+    highest_nc: u32,
+    // bit i set: highest_nc - i was used
+    used: u64,
+}
+
+impl Nonce {
+    fn new() -> Self {
+        // nc 0 counts as used, they start at 1
+        Nonce { issued: Instant::now(), highest_nc: 0, used: 1 }
+    }
+    /// Marks `nc` used, false if it was before or is too old to tell.
+    fn use_nc(&mut self, nc: u32) -> bool {
+        if nc > self.highest_nc {
+            let shift = nc - self.highest_nc;
+            self.used = if shift >= NC_WINDOW { 1 } else { (self.used << shift) | 1 };
+            self.highest_nc = nc;
+            return true;
+        }
+        let offset = self.highest_nc - nc;
+        if offset >= NC_WINDOW || self.used & (1 << offset) != 0 {
+            return false;
+        }
+        self.used |= 1 << offset;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn hash(&self, data: &str) -> String {
+        match self {
+            Algorithm::Md5 => format!("{:x}", Md5::digest(data.as_bytes())),
+            Algorithm::Sha256 => format!("{:x}", Sha256::digest(data.as_bytes())),
+        }
+    }
+}
+
+/// `WWW-Authenticate` value offering SHA-256 and, for older clients, MD5
+/// with a fresh nonce. `stale` tells the client its credentials were fine
+/// and only the nonce needs renewing.
+pub fn challenge(realm: &str, stale: bool) -> String {
+    let nonce = issue_nonce();
+    let stale = if stale { ", stale=true" } else { "" };
+    ["SHA-256", "MD5"].iter()
+        .map(|algorithm| format!("Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"{}", realm, algorithm, nonce, stale))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn issue_nonce() -> String {
+    let nonce = crate::passwd::random_hex(16);
+    let mut nonces = NONCES.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    if nonces.len() >= MAX_NONCES {
+        nonces.retain(|_, nonce| nonce.issued.elapsed() < NONCE_LIFETIME);
+    }
+    if nonces.len() >= MAX_NONCES {
+        if let Some(oldest) = nonces.iter().min_by_key(|(_, nonce)| nonce.issued).map(|(nonce, _)| nonce.clone()) {
+            nonces.remove(&oldest);
+        }
+    }
+    nonces.insert(nonce.clone(), Nonce::new());
+    nonce
+}
+
+/// Accepts `nc` only if it wasn't used with the nonce before, in any order,
+/// so a captured request can't be sent again.
+fn use_nonce(nonce: &str, nc: u32) -> Result<(), AuthError> {
+    let mut nonces = NONCES.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    let state = match nonces.get_mut(nonce) {
+        Some(val) => val,
+        // unknown to this server, e.g. after a restart
+        None => return Err(AuthError::Stale),
+    };
+    if state.issued.elapsed() >= NONCE_LIFETIME {
+        nonces.remove(nonce);
+        return Err(AuthError::Stale);
+    }
+    if !state.use_nc(nc) {
+        return Err(AuthError::Denied("nonce count reused".to_string()));
+    }
+    Ok(())
+}
+
+/// RFC 7616 digest access authentication with `qop=auth`. The server never
+/// sees the password, only `H(username:realm:password)` from
+/// ./private/.htdigest, one `username:realm:md5[:sha256]` line per user.
+#[derive(Debug)]
+pub struct DigestAuth {
+    credentials_str: String,
+    method: String,
+    // request target, query included
+    uri: String,
+    ip: Option<IpAddr>,
+    params: HashMap<String, String>,
+}
+
+impl DigestAuth {
+    pub fn new(str: &str, method: &str, uri: &str, ip: Option<IpAddr>) -> Self {
+        DigestAuth {
+            credentials_str: str.to_owned(),
+            method: method.to_owned(),
+            uri: uri.to_owned(),
+            ip,
+            params: HashMap::new(),
+        }
+    }
+    pub fn username(&self) -> &str {
+        self.params.get("username").map_or("", |str| str)
+    }
+    fn param(&self, name: &str) -> Result<&str, AuthError> {
+        self.params.get(name)
+            .map(|str| str.as_str())
+            .ok_or_else(|| AuthError::Denied(format!("digest parameter {} missing", name)))
+    }
+    /// Checks the response, the nonce and nonce count it was made with on
+    /// success. Whether those were used before is up to the caller, a
+    /// reused count is no wrong password.
+    fn validate(&self) -> Result<(String, u32), AuthError> {
+        let s_conf = crate::s_conf().ok_or_else(|| AuthError::Internal("S_CONF uninitialized".to_string()))?;
+        let algorithm = match self.params.get("algorithm").map_or("MD5", |str| str).to_uppercase().as_str() {
+            "MD5" => Algorithm::Md5,
+            "SHA-256" => Algorithm::Sha256,
+            other => return Err(AuthError::Denied(format!("digest algorithm {} not supported", other))),
+        };
+        if self.param("qop")? != "auth" {
+            return Err(AuthError::Denied("only qop=auth is supported".to_string()));
+        }
+        if self.params.get("userhash").is_some_and(|userhash| userhash == "true") {
+            return Err(AuthError::Denied("userhash not supported".to_string()));
+        }
+        if self.param("realm")? != s_conf.realm {
+            return Err(AuthError::Denied("wrong realm".to_string()));
+        }
+        // the response covers the uri, it has to be the one requested
+        if self.param("uri")? != self.uri {
+            return Err(AuthError::Denied("digest uri doesn't match the request".to_string()));
+        }
+        let nonce = self.param("nonce")?;
+        let nc_str = self.param("nc")?;
+        let nc = u32::from_str_radix(nc_str, 16).map_err(|_| AuthError::Denied("invalid nonce count".to_string()))?;
+        let cnonce = self.param("cnonce")?;
+
+        let users = crate::user_store(crate::users::HTDIGEST_FILE);
+        let stored = users.password(self.username())
+            .map_err(AuthError::Internal)?
+            .ok_or_else(|| AuthError::Denied(crate::lockout::FAILED_MSG.to_string()))?;
+        let ha1 = stored_ha1(&stored, &s_conf.realm, algorithm)
+            .ok_or_else(|| AuthError::Denied(crate::lockout::FAILED_MSG.to_string()))?;
+        let ha2 = algorithm.hash(&format!("{}:{}", self.method, self.uri));
+        let expected = algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc_str, cnonce, ha2));
+        if !crate::passwd::consteq(&expected, &self.param("response")?.to_lowercase()) {
+            return Err(AuthError::Denied(crate::lockout::FAILED_MSG.to_string()));
+        }
+        Ok((nonce.to_owned(), nc))
+    }
+}
+
+impl Auth for DigestAuth {
+    fn authorize(&mut self) -> Result<(), AuthError> {
+        let params_str = self.credentials_str.strip_prefix("Digest ")
+            .ok_or_else(|| AuthError::Denied("credentials required, but not provided".to_string()))?;
+        self.params = parse_params(params_str);
+        let mut nonce_count = None;
+        crate::lockout::attempt(self.ip, self.username(), || {
+            nonce_count = Some(self.validate()?);
+            Ok(())
+        })?;
+        // only a correct response may use up a nonce count
+        if let Some((nonce, nc)) = nonce_count {
+            use_nonce(&nonce, nc)?;
+        }
+        crate::totp::refuse_enrolled(self.username())
+    }
+}
+
+/// `realm:md5[:sha256]`, the part of a .htdigest line after the username.
+fn stored_ha1<'a>(stored: &'a str, realm: &str, algorithm: Algorithm) -> Option<&'a str> {
+    let mut fields = stored.split(':');
+    if fields.next()? != realm {
+        return None;
+    }
+    let md5 = fields.next();
+    let sha256 = fields.next();
+    match algorithm {
+        Algorithm::Md5 => md5,
+        Algorithm::Sha256 => sha256,
+    }
+}
+
+/// The `realm:md5:sha256` value stored for a new password.
+pub fn ha1_entry(username: &str, realm: &str, password: &str) -> String {
+    let data = format!("{}:{}:{}", username, realm, password);
+    format!("{}:{}:{}", realm, Algorithm::Md5.hash(&data), Algorithm::Sha256.hash(&data))
+}
+
+/// `key=value` and `key="quoted, value"` pairs separated by commas.
+fn parse_params(str: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = str.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let after = after.trim_start();
+        let (value, remainder) = if let Some(quoted) = after.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((index, char)) = chars.next() {
+                match char {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    },
+                    '"' => {
+                        end = index + 1;
+                        break;
+                    },
+                    char => value.push(char),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim().to_owned(), &after[end..])
+        };
+        params.insert(key, value);
+        rest = remainder.trim_start().trim_start_matches(',');
+    }
+    params
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use super::{issue_nonce, parse_params, stored_ha1, ha1_entry, use_nonce, Algorithm, DigestAuth, NC_WINDOW};
+    use crate::server::{Auth, AuthError, ServerConfig};
+
+    #[test]
+    fn rfc7616_example() {
+        // section 3.9.1, user Mufasa with password "Circle of Life"
+        let entry = ha1_entry("Mufasa", "http-auth@example.org", "Circle of Life");
+        let header = r#"username="Mufasa", realm="http-auth@example.org", uri="/dir/index.html",
+            algorithm=SHA-256, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", nc=00000001,
+            cnonce="f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ", qop=auth,
+            response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1",
+            opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#;
+        let params = parse_params(header);
+        assert_eq!(params["uri"], "/dir/index.html");
+        assert_eq!(params["qop"], "auth");
+        for (algorithm, response) in [
+            (Algorithm::Sha256, "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"),
+            (Algorithm::Md5, "8ca523f5e9506fed4657c9700eebdbec"),
+        ] {
+            let ha1 = stored_ha1(&entry, "http-auth@example.org", algorithm).unwrap();
+            let ha2 = algorithm.hash("GET:/dir/index.html");
+            let expected = algorithm.hash(&format!("{}:{}:{}:{}:auth:{}", ha1, params["nonce"], params["nc"], params["cnonce"], ha2));
+            assert_eq!(expected, response);
+        }
+    }
+
+    #[test]
+    fn nonce_counts_out_of_order() {
+        let nonce = issue_nonce();
+        for nc in [2, 1, 4, 3, NC_WINDOW + 3] {
+            assert!(use_nonce(&nonce, nc).is_ok(), "{}", nc);
+        }
+        // used, never issued or too old to tell
+        for nc in [3, 0, 2] {
+            assert!(use_nonce(&nonce, nc).is_err(), "{}", nc);
+        }
+        assert!(use_nonce(&nonce, 5).is_ok());
+    }
+
+    #[test]
+    fn reused_nonce_count_no_failed_login() {
+        let private_dir = std::env::temp_dir().join(format!("hello_server_digest_{}", std::process::id()));
+        std::fs::create_dir_all(&private_dir).unwrap();
+        let mut s_conf = ServerConfig::new();
+        s_conf.private_dir = private_dir.display().to_string();
+        let entry = ha1_entry("alice", &s_conf.realm, "secret");
+        std::fs::write(private_dir.join(".htdigest"), format!("alice:{}\n", entry)).unwrap();
+        let ha1 = stored_ha1(&entry, &s_conf.realm, Algorithm::Md5).unwrap().to_owned();
+        let realm = s_conf.realm.clone();
+        let previous = crate::VHOST_CONF.with(|current| current.replace(Some(Arc::new(s_conf))));
+
+        let nonce = issue_nonce();
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 38)));
+        let authorize = |nc: &str| {
+            let response = Algorithm::Md5.hash(&format!("{}:{}:{}:c:auth:{}", ha1, nonce, nc, Algorithm::Md5.hash("GET:/")));
+            let header = format!(
+                "Digest username=\"alice\", realm=\"{}\", uri=\"/\", qop=auth, nonce=\"{}\", nc={}, cnonce=\"c\", response=\"{}\"",
+                realm, nonce, nc, response
+            );
+            DigestAuth::new(&header, "GET", "/", ip).authorize()
+        };
+        assert_eq!(authorize("00000001"), Ok(()));
+        // a browser retrying more often than the free failures
+        for _ in 0..10 {
+            assert_eq!(authorize("00000001"), Err(AuthError::Denied("nonce count reused".to_string())));
+        }
+        assert_eq!(authorize("00000002"), Ok(()));
+
+        crate::VHOST_CONF.with(|current| current.replace(previous));
+        std::fs::remove_dir_all(&private_dir).unwrap();
+    }
+}
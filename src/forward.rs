@@ -0,0 +1,241 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    http::{Request, Response},
+    server::{Auth, AuthError},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+// the gateway's answer, a login page at most
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+// passed on to the client when the gateway says no, so its login redirect works
+const DENIAL_HEADERS: [&str; 3] = ["Location", "WWW-Authenticate", "Set-Cookie"];
+
+/// Asks an auth gateway about every request, the way reverse proxies do
+/// forward auth: a `GET` to `forward_auth_url` with the client's
+/// `Authorization` and `Cookie` headers plus `X-Forwarded-*` describing the
+/// request. Any 2xx lets the request in as the user named in the
+/// `forward_auth_user_header` of the answer, anything else is sent back to
+/// the client as the gateway's verdict.
+#[derive(Debug)]
+pub struct ForwardAuth {
+    request_str: String,
+    username: String,
+    denial: Option<Response>,
+}
+
+impl ForwardAuth {
+    pub fn new(request: &Request) -> Self {
+        let s_conf = crate::s_conf();
+        let url = s_conf.as_ref().map_or("", |s_conf| s_conf.forward_auth_url.as_str());
+        let (host, path) = split_url(url).unwrap_or_default();
+        let mut request_str = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", path, host);
+        for name in ["Authorization", "Cookie"] {
+            if let Some(value) = request.headers.get(name) {
+                request_str.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        let proto = if request.peer.tls { "https" } else { "http" };
+        request_str.push_str(&format!("X-Forwarded-Method: {}\r\n", request.method));
+        request_str.push_str(&format!("X-Forwarded-Proto: {}\r\n", proto));
+        request_str.push_str(&format!("X-Forwarded-Host: {}\r\n", request.headers.get("Host").map_or("", |str| str)));
+        request_str.push_str(&format!("X-Forwarded-Uri: {}\r\n", request.target));
+        if let Some(addr) = request.peer.addr {
+            request_str.push_str(&format!("X-Forwarded-For: {}\r\n", addr));
+        }
+        request_str.push_str("\r\n");
+        ForwardAuth {
+            request_str,
+            username: String::new(),
+            denial: None,
+        }
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    /// What to answer the client with after a denial.
+    pub fn take_denial(&mut self) -> Option<Response> {
+        self.denial.take()
+    }
+    /// The verdict of the gateway at `url`, the username on a 2xx.
+    fn check(&mut self, url: &str, user_header: &str) -> Result<String, AuthError> {
+        let response = ask_gateway(url, &self.request_str).map_err(|e| AuthError::Internal(format!("forward auth\n{}", e)))?;
+        let GatewayResponse { status, headers, body } = parse_response(&response)
+            .ok_or_else(|| AuthError::Internal("forward auth\ninvalid response from the gateway".to_string()))?;
+        if !(200..300).contains(&status) {
+            let mut denial = Response::from(body);
+            denial.status = status;
+            for (name, value) in headers.iter().filter(|(name, _)| DENIAL_HEADERS.iter().any(|denial| denial.eq_ignore_ascii_case(name))) {
+                denial.headers.insert(name.to_string(), value.to_string());
+            }
+            self.denial = Some(denial);
+            return Err(AuthError::Denied(format!("gateway answered {}", status)));
+        }
+        headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(user_header))
+            .map(|(_, value)| value.to_string())
+            .filter(|username| !username.is_empty())
+            .ok_or_else(|| AuthError::Internal(format!("forward auth\ngateway allowed the request without a {} header", user_header)))
+    }
+}
+
+impl Auth for ForwardAuth {
+    fn authorize(&mut self) -> Result<(), AuthError> {
+        let s_conf = crate::s_conf().ok_or_else(|| AuthError::Internal("S_CONF uninitialized".to_string()))?;
+        self.username = self.check(&s_conf.forward_auth_url, &s_conf.forward_auth_user_header)?;
+        Ok(())
+    }
+}
+
+fn ask_gateway(url: &str, request_str: &str) -> Result<Vec<u8>, String> {
+    let (host, _) = split_url(url).ok_or_else(|| format!("forward_auth_url '{}' isn't an http:// url", url))?;
+    let mut stream = connect(&host)?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.write_all(request_str.as_bytes()).map_err(|e| format!("{}: \"{}\"", e, host))?;
+    // a gateway keeping the connection open doesn't end the response,
+    // its Content-Length does
+    let mut response = Vec::new();
+    let mut chunk = [0; 4096];
+    let mut stream = stream.take(MAX_RESPONSE_SIZE);
+    while response_length(&response).is_none_or(|length| response.len() < length) {
+        match stream.read(&mut chunk).map_err(|e| format!("{}: \"{}\"", e, host))? {
+            0 => break,
+            count => response.extend_from_slice(&chunk[..count]),
+        }
+    }
+    Ok(response)
+}
+
+/// Tries every address of `host` in turn, `localhost` is often `::1` first
+/// while the gateway only listens on 127.0.0.1.
+fn connect(host: &str) -> Result<TcpStream, String> {
+    let mut last_error = format!("no address for \"{}\"", host);
+    for addr in host.to_socket_addrs().map_err(|e| format!("{}: \"{}\"", e, host))? {
+        match TcpStream::connect_timeout(&addr, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = format!("{}: \"{}\" ({})", e, host, addr),
+        }
+    }
+    Err(last_error)
+}
+
+/// Head and body size of the response once its head is in and has a
+/// Content-Length.
+fn response_length(response: &[u8]) -> Option<usize> {
+    let head_end = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&response[..head_end]).ok()?;
+    let content_length = head.split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))?
+        .1.trim().parse::<usize>().ok()?;
+    Some(head_end + 4 + content_length)
+}
+
+/// `host:port` and path of an `http://` url, port 80 when there's none.
+fn split_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    // a port or the closing bracket of an ipv6 address ends it
+    let host = if host.rsplit_once(':').is_some_and(|(_, port)| !port.contains(']')) {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    Some((host, path.to_owned()))
+}
+
+struct GatewayResponse<'a> {
+    status: u16,
+    headers: Vec<(&'a str, &'a str)>,
+    body: &'a [u8],
+}
+
+fn parse_response(response: &[u8]) -> Option<GatewayResponse<'_>> {
+    let head_end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(response.len());
+    let head = std::str::from_utf8(&response[..head_end]).ok()?;
+    let body = response.get(head_end + 4..).unwrap_or_default();
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    Some(GatewayResponse { status, headers, body })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::Arc,
+        thread,
+    };
+
+    use super::{split_url, AuthError, ForwardAuth};
+    use crate::{
+        http::{Request, RequestMethod},
+        server::{Auth, ServerConfig},
+    };
+
+    /// Plays the gateway for one request each: alice with her cookie, a
+    /// redirect to the SSO login otherwise. It keeps the connections open
+    /// until the client closes them.
+    fn stub_gateway(requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // localhost may be tried as ::1 first
+        let url = format!("http://localhost:{}/verify", listener.local_addr().unwrap().port());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut head = String::new();
+                let mut reader = BufReader::new(&stream);
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let response = if head.contains("Cookie: sso=alice") && head.contains("X-Forwarded-Uri: /content?x=1") {
+                    "HTTP/1.1 200 OK\r\nRemote-User: alice\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    "HTTP/1.1 302 Found\r\nLocation: https://sso.example.org/login\r\nContent-Length: 0\r\n\r\n"
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+                io::copy(&mut stream, &mut io::sink()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn stub_gateway_decides() {
+        let mut s_conf = ServerConfig::new();
+        s_conf.forward_auth_url = stub_gateway(2);
+        s_conf.forward_auth_user_header = "remote-user".to_owned();
+        let previous = crate::VHOST_CONF.with(|current| current.replace(Some(Arc::new(s_conf))));
+        let request = |cookie: &str| {
+            let mut request = Request::build(RequestMethod::Get, "/content", b"");
+            request.target = "/content?x=1".to_owned();
+            request.headers.insert("Cookie".to_owned(), cookie.to_owned());
+            request
+        };
+        let mut auth = ForwardAuth::new(&request("sso=alice"));
+        assert_eq!(auth.authorize(), Ok(()));
+        assert_eq!(auth.username(), "alice");
+        let mut auth = ForwardAuth::new(&request("sso=mallory"));
+        assert!(matches!(auth.authorize(), Err(AuthError::Denied(_))));
+        crate::VHOST_CONF.with(|current| current.replace(previous));
+        let denial = auth.take_denial().unwrap();
+        assert_eq!(denial.status, 302);
+        assert_eq!(denial.headers["Location"], "https://sso.example.org/login");
+        assert_eq!(split_url("http://[::1]"), Some(("[::1]:80".to_owned(), "/".to_owned())));
+        assert_eq!(split_url("https://sso.example.org/"), None);
+    }
+}
@@ -5,6 +5,8 @@ pub struct FilesHtml {
     pub path: String,
     pub dir_entries: Result<Vec<(String, bool)>, String>,
     pub is_file: bool,
+    // of the login session, uploads have to send it back
+    pub csrf_token: Option<String>,
 }
 
 impl FilesHtml {
@@ -16,13 +18,14 @@ impl FilesHtml {
             path: path.to_string(),
             dir_entries: Ok(Vec::with_capacity(10)),
             is_file: false,
+            csrf_token: None,
         };
         f.read();
         f
     }
     
     fn syspath(&self) -> String {
-        format!("./public{}", &self.path)
+        crate::root_path(&self.path)
     }
     
     pub fn read(&mut self) {
@@ -33,7 +36,7 @@ impl FilesHtml {
             self.dir_entries = match path.read_dir() {
                 Ok(read_dir) => Ok(read_dir
                     .map(|res| match res {
-                        Ok(dir) => match dir.path().components().last() {
+                        Ok(dir) => match dir.path().components().next_back() {
                             Some(comp) => (
                                 comp.as_os_str().to_string_lossy().to_string(),
                                 dir.path().is_file(),
@@ -47,6 +50,22 @@ impl FilesHtml {
                     })
                     .collect()),
                 Err(e) => Err(format!("{}\n{}", e, &syspath)),
+            };
+            self.add_mount_points();
+        }
+    }
+
+    // mounts show up in the listing of their parent, root needn't have a directory there
+    fn add_mount_points(&mut self) {
+        let (Ok(entries), Some(s_conf)) = (&mut self.dir_entries, crate::s_conf()) else {
+            return;
+        };
+        let path = crate::acl::normalize(&self.path).unwrap_or_default();
+        for mount in &s_conf.mounts {
+            let (parent, name) = mount.prefix.rsplit_once('/').unwrap_or_default();
+            let parent = if parent.is_empty() { "/" } else { parent };
+            if parent == path && !entries.iter().any(|(entry, _)| entry == name) {
+                entries.push((name.to_owned(), false));
             }
         }
     }
@@ -80,7 +99,7 @@ impl FilesHtml {
                         &self.path,
                         entry.0,
                         if !entry.1 { "" } else { "download class=\"dl_link\"" },// is folder?
-                        metadata_len.to_string(),
+                        metadata_len,
                         if !entry.1 { "&#x1F4C1 " } else { "" },
                         entry.0
                     );
@@ -92,9 +111,11 @@ impl FilesHtml {
         body_html.push_str("
         <button onclick=\"DownloadAll()\">DL all</button>
         ");
-        body_html.push_str("
+        let csrf_field = self.csrf_token.as_ref().map_or(String::new(), |csrf_token| format!("<input type=\"hidden\" name=\"csrf\" value=\"{}\" />", csrf_token));
+        body_html.push_str(&format!("
         <hr></hr>
         <form method=\"post\" enctype=\"multipart/form-data\">
+            {}
             <div>
                 <p><label for=\"file\">Upload to this folder</label></p>
                 <p></p><input type=\"file\" id=\"file\" name=\"file\" multiple /></p>
@@ -103,7 +124,10 @@ impl FilesHtml {
                 <button>Upload</button>
             </div>
         </form>
-        "); 
+        ", csrf_field));
+        if self.csrf_token.is_some() {
+            body_html.push_str("<p><a href=\"/logout\">Log out</a></p>");
+        }
         body_html.push_str("
         <script>
           const mega_bytes_per_second = 7;
@@ -186,7 +210,7 @@ mod tests {
                 println!("123")
             },
             Err(e) => {
-                println!("{}", e.to_string())
+                println!("{}", e)
             }
         }
     }
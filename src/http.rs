@@ -2,13 +2,20 @@ use std::{
     collections::HashMap,
     error::Error,
     fs,
-    io::{self, BufRead, BufReader},
-    net::TcpStream,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
 };
 
-use crate::server::{Auth, AuthScheme, BasicAuth};
+use crate::acl::{self, Permission};
+use crate::digest::{self, DigestAuth};
+use crate::forward::ForwardAuth;
+use crate::session;
+use crate::token::{BearerAuth, TokenScope};
+use crate::server::{Auth, AuthError, AuthScheme, BasicAuth};
 
 const VERSION: &str = "HTTP/1.1"; // doesn't metter
+const MAX_FORM_SIZE: usize = 16 * 1024;
 // const BUF_STRING_LIMIT: usize = 8192 * 100;
 // const FILE_BUF_SIZE_LIMIT: usize = 10 * 1024 * 1024; // 10 Mb
 // const FILE_SIZE_LIMIT: usize = 1024 * 1024 * 1024; // 1Gb
@@ -17,6 +24,7 @@ const VERSION: &str = "HTTP/1.1"; // doesn't metter
 pub enum RequestMethod {
     Get,
     Post,
+    Delete,
     Other(String),
 }
 
@@ -26,28 +34,55 @@ impl RequestMethod {
         match str.as_str() {
             "get" => RequestMethod::Get,
             "post" => RequestMethod::Post,
+            "delete" => RequestMethod::Delete,
             other => RequestMethod::Other(other.to_owned()),
         }
     }
 }
 
-impl ToString for RequestMethod {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for RequestMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             Self::Get => "GET",
             Self::Post => "POST",
+            Self::Delete => "DELETE",
             Self::Other(other) => other,
-        }
-        .to_owned()
+        };
+        write!(f, "{}", str)
     }
 }
 
+/// What the connection itself tells about the client.
+#[derive(Debug, Default, Clone)]
+pub struct Peer {
+    pub addr: Option<IpAddr>,
+    // subject common name of a verified TLS client certificate
+    pub cert_name: Option<String>,
+    // came in over https
+    pub tls: bool,
+    // accepted on a listener with admin = true
+    pub admin: bool,
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: RequestMethod,
     pub headers: HashMap<String, String>,
+    // path part of the request target, without the query
     pub url: String,
+    pub query: String,
+    // request target as sent
+    pub target: String,
     pub body: Vec<u8>,
+    pub peer: Peer,
+    // set by authorize, None for anonymous requests
+    pub user: Option<String>,
+    // what an api token limits the request to
+    pub scope: Option<TokenScope>,
+    // of the login session, forms posting back need it
+    pub csrf_token: Option<String>,
+    // the csrf token is still to come as a field of the multipart body
+    csrf_pending: bool,
 }
 
 impl Request {
@@ -57,7 +92,14 @@ impl Request {
             method: RequestMethod::Get,
             headers: HashMap::new(),
             url: String::from(""),
+            query: String::new(),
+            target: String::new(),
             body: Vec::new(),
+            peer: Peer::default(),
+            user: None,
+            scope: None,
+            csrf_token: None,
+            csrf_pending: false,
         }
     }
     #[allow(dead_code)]
@@ -66,7 +108,14 @@ impl Request {
             method,
             headers: HashMap::new(),
             url: url.to_owned(),
+            query: String::new(),
+            target: url.to_owned(),
             body: body.to_owned(),
+            peer: Peer::default(),
+            user: None,
+            scope: None,
+            csrf_token: None,
+            csrf_pending: false,
         }
     }
 
@@ -84,9 +133,18 @@ impl Request {
             0
         }
         .max(word_start);
-        self.url = request_line[word_start..word_end]
-            .trim()
-            .replace("%20", " ");
+        self.target = request_line[word_start..word_end].trim().to_owned();
+        let (path, query) = self.target.split_once('?').unwrap_or((&self.target, ""));
+        self.url = path.replace("%20", " ");
+        self.query = query.to_owned();
+    }
+
+    /// Value of `name` in the query string, as sent.
+    pub fn query_value(&self, name: &str) -> Option<&str> {
+        self.query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
     }
 
     fn parse_header(&mut self, buf: &str) {
@@ -99,9 +157,9 @@ impl Request {
         self.headers.insert(name.to_owned(), value.to_owned());
     }
 
-    fn read_body_from_buf(
+    fn read_body_from_buf<R: Read>(
         &mut self,
-        buf: &mut SafeBuf,
+        buf: &mut SafeBuf<R>,
     ) -> Result<usize, Box<dyn Error>> {
         let ctype_str = match self.headers.get("Content-Type") {
             Some(str) => str,
@@ -167,25 +225,48 @@ impl Request {
             drop(data_remainder);
             let line = buf.read_line()?;
             // println!("#1 line: '{}'", line);
+            let csrf_field = line.contains("name=\"csrf\"") && !line.contains("filename=\"");
             let mut file_name = "last_upload";
             for str in line.split(';') {
                 let file_name_pos = str.find("filename=\"");
                 if let Some(pos) = file_name_pos {
                     file_name = &str[pos+10..str.len()-1];
+                    // only the name, the url decides the directory
+                    file_name = file_name.rsplit(['/', '\\']).next().unwrap_or_default();
+                    if file_name.is_empty() || file_name == "." || file_name == ".." {
+                        file_name = "last_upload";
+                    }
                 }
             }
-            let _line = buf.read_line()?;// ignore Content-Type
-            // println!("#2 line: '{}'", _line);
+            if !csrf_field {
+                let _line = buf.read_line()?;// ignore Content-Type
+                // println!("#2 line: '{}'", _line);
+            }
             let line = buf.read_line()?;// must be empty - delimit beginning of file data
             // println!("#3 line: '{}'", line);
             if !line.is_empty() {
                 return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "no data start delimiter found (\\r\\n\\r\\n)")));
             }
+            // the forms put it ahead of the files, nothing gets written before it
+            if csrf_field {
+                buf.clear_file_name();
+                let sent = String::from_utf8_lossy(&buf.read_until(boundary_start)?).into_owned();
+                if let Some(csrf_token) = &self.csrf_token {
+                    if !crate::passwd::consteq(&sent, csrf_token) {
+                        return Err(Box::new(io::Error::new(io::ErrorKind::PermissionDenied, "csrf token wrong")));
+                    }
+                    self.csrf_pending = false;
+                }
+                continue;
+            }
+            if self.csrf_pending {
+                return Err(Box::new(io::Error::new(io::ErrorKind::PermissionDenied, "csrf token missing")));
+            }
             // println!("url: {}", self.url);
             let file_path = match self.url.as_str() {
-                "/upload" => format!("./public/content/upload/{}", file_name),
+                "/upload" => crate::root_path(&format!("/content/upload/{}", file_name)),
                 url => {
-                    format!("./public/{}/{}", url, file_name)
+                    crate::root_path(&format!("{}/{}", url, file_name))
                 }
             };
             // println!("file_path: '{}'", file_path);
@@ -199,8 +280,8 @@ impl Request {
         Ok(1)
     }
 
-    fn get_msg_str(header: &str, msg: &str) -> String {
-        let response_pattern = match fs::read("./public/static/msg.html") {
+    pub fn get_msg_str(header: &str, msg: &str) -> String {
+        let response_pattern = match fs::read(crate::root_path("/static/msg.html")) {
             Ok(val) => val,
             Err(_e) => header.as_bytes().to_owned(),
         };
@@ -208,38 +289,168 @@ impl Request {
         response_str.replace("{header}", header).replace("{msg}", msg)
     }
 
-    pub fn authorize(&self, auth_scheme: &AuthScheme) -> Result<(), Response> {
-        if let AuthScheme::None = auth_scheme {
+    /// Authenticates the client with whichever of `auth_schemes` its
+    /// credentials are for, then checks the token scope and the ACL, if
+    /// there are any, for what the request is about to do. With an ACL a
+    /// request without credentials goes on as anonymous and gets as far as
+    /// the ACL lets it. Deletes need an ACL rule allowing them.
+    pub fn authorize(&mut self, auth_schemes: &[AuthScheme]) -> Result<(), Response> {
+        if auth_schemes.contains(&AuthScheme::Session) && session::is_public(self) {
             return Ok(());
+        }
+        let acl = match crate::acl_store().current() {
+            Ok(val) => val,
+            Err(e) => {
+                println!("ERROR: acl\n{}", e);
+                return Err(crate::internal_error());
+            }
         };
+        match self.credentials_scheme(auth_schemes) {
+            Some(auth_scheme) => self.authenticate(&auth_scheme, auth_schemes)?,
+            None if acl.is_none() && !auth_schemes.is_empty() => {
+                return Err(self.unauthorized("credentials required, but not provided", auth_schemes, false));
+            },
+            None => {},
+        }
+        // a cross-site form can send the cookie, but doesn't know the token
+        if let Some(csrf_token) = &self.csrf_token {
+            if matches!(self.method, RequestMethod::Post | RequestMethod::Delete) {
+                let multipart = matches!(self.method, RequestMethod::Post) && self.headers.get("Content-Type")
+                    .is_some_and(|ctype| ctype.starts_with("multipart/form-data"));
+                match self.headers.get("X-CSRF-Token") {
+                    Some(sent) if crate::passwd::consteq(sent, csrf_token) => {},
+                    // an upload form sends it in the body, checked while reading that
+                    None if multipart => self.csrf_pending = true,
+                    _ => {
+                        let mut response = Response::from("csrf token missing or wrong");
+                        response.status = 403;
+                        return Err(response);
+                    },
+                }
+            }
+        }
+        let (path, permission) = self.access();
+        let in_scope = self.scope.as_ref().is_none_or(|scope| scope.permits(path, permission));
+        let permitted = acl::permits(acl.as_deref(), self.user.as_deref(), path, permission);
+        if !permitted && self.user.is_none() && !auth_schemes.is_empty() {
+            return Err(self.unauthorized("login required", auth_schemes, false));
+        }
+        if !in_scope || !permitted {
+            let mut response = Response::from("forbidden");
+            response.status = 403;
+            return Err(response);
+        }
+        Ok(())
+    }
 
+    /// The first enabled scheme the client brought credentials for.
+    fn credentials_scheme(&self, auth_schemes: &[AuthScheme]) -> Option<AuthScheme> {
+        let authorization = self.headers.get("Authorization").map_or("", |str| str);
+        auth_schemes.iter()
+            .find(|auth_scheme| match auth_scheme {
+                AuthScheme::Basic => authorization.starts_with("Basic "),
+                AuthScheme::Digest => authorization.starts_with("Digest "),
+                AuthScheme::Bearer => authorization.starts_with("Bearer "),
+                AuthScheme::Mtls => self.peer.cert_name.is_some(),
+                AuthScheme::Session => session::cookie(self).is_some(),
+                // the gateway knows what counts as credentials
+                AuthScheme::Forward => true,
+                AuthScheme::None => false,
+            })
+            .cloned()
+    }
+
+    fn authenticate(&mut self, auth_scheme: &AuthScheme, auth_schemes: &[AuthScheme]) -> Result<(), Response> {
         let credentials_str = self.headers.get("Authorization").map_or("", |str| str);
-        let mut headers = HashMap::new();
         let auth_result = match auth_scheme {
             AuthScheme::None => return Ok(()),
             AuthScheme::Basic => {
-                headers.insert("WWW-Authenticate".to_owned(), "Basic".to_owned());
-                let mut auth = BasicAuth::new(credentials_str);
-                auth.authorize()
-            }
+                let mut auth = BasicAuth::new(credentials_str, self.peer.addr);
+                auth.authorize().map(|_| auth.username().to_owned())
+            },
+            AuthScheme::Digest => {
+                let mut auth = DigestAuth::new(credentials_str, &self.method.to_string(), &self.target, self.peer.addr);
+                auth.authorize().map(|_| auth.username().to_owned())
+            },
+            AuthScheme::Bearer => {
+                let mut auth = BearerAuth::new(credentials_str);
+                let result = auth.authorize().map(|_| auth.username().to_owned());
+                self.scope = Some(auth.scope().clone());
+                result
+            },
+            AuthScheme::Forward => {
+                let mut auth = ForwardAuth::new(self);
+                let result = auth.authorize().map(|_| auth.username().to_owned());
+                if let (Err(AuthError::Denied(_)), Some(denial)) = (&result, auth.take_denial()) {
+                    return Err(denial);
+                }
+                result
+            },
+            AuthScheme::Session => session::lookup(self).map(|session| {
+                self.csrf_token = Some(session.csrf_token);
+                session.user
+            }),
+            // the tls handshake already checked the certificate against the CA
+            AuthScheme::Mtls => self.peer.cert_name.clone()
+                .ok_or_else(|| AuthError::Denied("client certificate required".to_string())),
         };
         match auth_result {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                let mut response = Response::new();
-                response.str_fill(&e);
-                response.status = 401;
-                response.headers.extend(headers.into_iter());
-                Err(response)
-            }
+            Ok(user) => {
+                self.user = Some(user);
+                Ok(())
+            },
+            Err(AuthError::Internal(e)) => {
+                println!("ERROR: authorize\n{}", e);
+                Err(crate::internal_error())
+            },
+            Err(AuthError::Denied(e)) => Err(self.unauthorized(&e, auth_schemes, false)),
+            Err(AuthError::Stale) => Err(self.unauthorized("nonce expired", auth_schemes, true)),
+        }
+    }
+
+    /// 401 with a challenge for every header scheme, browsers that can log
+    /// in with a session are sent to the login page instead.
+    fn unauthorized(&self, msg: &str, auth_schemes: &[AuthScheme], stale: bool) -> Response {
+        let browser = self.headers.get("Accept").is_some_and(|accept| accept.contains("text/html"));
+        if auth_schemes.contains(&AuthScheme::Session) && matches!(self.method, RequestMethod::Get) && browser {
+            return session::redirect("/login");
+        }
+        unauthorized(msg, auth_schemes, stale)
+    }
+
+    /// The url path the request works on and what it does there.
+    pub fn access(&self) -> (&str, Permission) {
+        match self.method {
+            RequestMethod::Post if self.url == "/upload" => ("/content/upload", Permission::Write),
+            RequestMethod::Post => (&self.url, Permission::Write),
+            RequestMethod::Delete => (&self.url, Permission::Delete),
+            _ => (&self.url, Permission::Read),
         }
     }
 }
 
-impl TryFrom<&TcpStream> for Request {
-    type Error = Box<dyn std::error::Error>;
-    fn try_from(stream: &TcpStream) -> Result<Self, Self::Error> {
-        let mut buf = SafeBuf::try_from(stream)?;
+fn unauthorized(msg: &str, auth_schemes: &[AuthScheme], stale: bool) -> Response {
+    let mut response = Response::from(msg);
+    response.status = 401;
+    let s_conf = crate::s_conf();
+    let realm = s_conf.as_ref().map_or("hello_server", |s_conf| s_conf.realm.as_str());
+    let challenges: Vec<String> = auth_schemes.iter()
+        .filter_map(|auth_scheme| match auth_scheme {
+            AuthScheme::Basic => Some(format!("Basic realm=\"{}\"", realm)),
+            AuthScheme::Digest => Some(digest::challenge(realm, stale)),
+            AuthScheme::Bearer => Some(format!("Bearer realm=\"{}\"", realm)),
+            AuthScheme::Mtls | AuthScheme::Session | AuthScheme::Forward | AuthScheme::None => None,
+        })
+        .collect();
+    if !challenges.is_empty() {
+        response.headers.insert("WWW-Authenticate".to_owned(), challenges.join(", "));
+    }
+    response
+}
+
+impl Request {
+    /// Reads the request line and headers, leaving the body in `buf`.
+    pub fn read_head<R: Read>(buf: &mut SafeBuf<R>) -> Result<Self, Box<dyn Error>> {
         // println!("'{}'", String::from_utf8_lossy(&buf._buf()));
         let line = buf.read_line()?;
         // println!("'{}'", line);
@@ -256,17 +467,115 @@ impl TryFrom<&TcpStream> for Request {
             }
             request.parse_header(&line);
         }
-        if let RequestMethod::Post = request.method {
-            request.read_body_from_buf(&mut buf)?;
-        }
-        // println!("{:#?}", request);
         Ok(request)
     }
+    /// Reads whatever follows the headers, for POST that's the upload.
+    pub fn read_body<R: Read>(&mut self, buf: &mut SafeBuf<R>) -> Result<(), Box<dyn Error>> {
+        match self.method {
+            // small forms like the login are kept in body
+            RequestMethod::Post if self.is_form() => {
+                self.body = self.read_form_len(buf)?;
+            },
+            RequestMethod::Post => {
+                self.read_body_from_buf(buf)?;
+            },
+            _ => {},
+        }
+        // println!("{:#?}", self);
+        Ok(())
+    }
+
+    /// Reads a small urlencoded form into body and nothing else, for the
+    /// public paths, where an upload must not get to disk.
+    pub fn read_form<R: Read>(&mut self, buf: &mut SafeBuf<R>) -> Result<(), Response> {
+        if !self.is_form() {
+            let mut response = Response::from("Content-Type must be application/x-www-form-urlencoded");
+            response.status = 415;
+            return Err(response);
+        }
+        match self.read_form_len(buf) {
+            Ok(body) => {
+                self.body = body;
+                Ok(())
+            },
+            Err(e) => {
+                let mut response = Response::from(&format!("Request::read_form\n{}", e)[..]);
+                response.status = 400;
+                Err(response)
+            },
+        }
+    }
+
+    fn is_form(&self) -> bool {
+        self.headers.get("Content-Type")
+            .is_some_and(|ctype| ctype.starts_with("application/x-www-form-urlencoded"))
+    }
+
+    fn read_form_len<R: Read>(&self, buf: &mut SafeBuf<R>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let len: usize = self.headers.get("Content-Length").and_then(|len| len.parse().ok()).unwrap_or(0);
+        if len > MAX_FORM_SIZE {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "form too large")));
+        }
+        buf.read_len(len)
+    }
+}
+
+
+/// Anything a connection can be served over, plain TCP or TLS.
+pub trait Transport: Read + Write + Send {
+    fn peer(&self) -> Peer {
+        Peer::default()
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer(&self) -> Peer {
+        Peer {
+            addr: self.peer_addr().ok().map(|addr| addr.ip()),
+            cert_name: None,
+            tls: false,
+            admin: false,
+        }
+    }
+}
+
+/// Connection handle shared by the request reader and whoever writes the
+/// response, like `&TcpStream` but for any `Transport`.
+#[derive(Clone)]
+pub struct SharedStream(Arc<Mutex<Box<dyn Transport>>>);
+
+impl SharedStream {
+    pub fn new<T: Transport + 'static>(stream: T) -> Self {
+        SharedStream(Arc::new(Mutex::new(Box::new(stream))))
+    }
+    pub fn peer(&self) -> Peer {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).peer()
+    }
 }
 
+impl Read for &SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).read(buf)
+    }
+}
+
+impl Write for &SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).flush()
+    }
+}
 
-struct SafeBuf<'a> {
-    buf_reader: BufReader<&'a TcpStream>,
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+pub struct SafeBuf<R: Read> {
+    buf_reader: BufReader<R>,
     buf: Vec<u8>,
     index: usize,
     buf_len: usize,
@@ -275,9 +584,8 @@ struct SafeBuf<'a> {
     file_size: usize,
 }
 
-impl<'a> TryFrom<&'a TcpStream> for SafeBuf<'a> {
-    type Error = Box<dyn std::error::Error>;
-    fn try_from(stream: &'a TcpStream) -> Result<Self, Self::Error> {
+impl<R: Read> SafeBuf<R> {
+    pub fn build(stream: R) -> Result<Self, Box<dyn Error>> {
         let mut safe_buf = SafeBuf {
             buf_reader: BufReader::new(stream),
             buf: Vec::with_capacity(8192),
@@ -292,7 +600,7 @@ impl<'a> TryFrom<&'a TcpStream> for SafeBuf<'a> {
     }
 }
 
-impl<'a> SafeBuf<'a> {
+impl<R: Read> SafeBuf<R> {
 
     fn update_buf(&mut self) -> Result<(), Box<dyn Error>> {
         self.buf_len = self.buf.len();
@@ -318,7 +626,8 @@ impl<'a> SafeBuf<'a> {
     }
 
     fn check_limits(&mut self) -> Result<(), Box<dyn Error>> {
-        let limits = crate::S_CONF.get().unwrap().limits();
+        let s_conf = crate::s_conf().unwrap();
+        let limits = s_conf.limits();
         // redirect output to file if file_path is set
         if let Some(file_path) = &self.file_path {
             if limits.file_buf_size_limit > 0 && self.buf_tail.len() > limits.file_buf_size_limit {
@@ -386,6 +695,27 @@ impl<'a> SafeBuf<'a> {
         Ok(std::mem::take(&mut self.buf_tail))
     }
 
+    /// The next `len` bytes, fewer if the stream ends first.
+    pub fn read_len(&mut self, len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            if self.buf_len > 0 && self.index >= self.buf_len {
+                self.buf.clear();
+                self.buf_reader.consume(self.buf_len);
+                self.buf_len = 0;
+                self.index = 0;
+            }
+            self.update_buf()?;
+            if self.buf_len == 0 {
+                break;
+            }
+            let take = (len - data.len()).min(self.buf_len - self.index);
+            data.extend_from_slice(&self.buf[self.index..self.index + take]);
+            self.index += take;
+        }
+        Ok(data)
+    }
+
     pub fn read_line(&mut self) -> Result<String, Box<dyn Error>> {
         Ok(String::from_utf8(self.read_until("\r\n".as_bytes())?)?)
     }
@@ -418,7 +748,7 @@ impl<'a> SafeBuf<'a> {
 
 }
 
-impl<'a> Drop for SafeBuf<'a> {
+impl<R: Read> Drop for SafeBuf<R> {
     fn drop(&mut self) {
         self.buf_reader.consume(self.buf_len);
     }
@@ -443,9 +773,9 @@ impl Response {
         let mut headers = HashMap::new();
         headers.insert(
             "echo-header".to_string(),
-            format!("{} {} {}", request.method.to_string(), request.url, VERSION),
+            format!("{} {} {}", request.method, request.url, VERSION),
         );
-        headers.extend(request.headers.clone().into_iter());
+        headers.extend(request.headers.clone());
         Response {
             status: 200,
             headers,
@@ -475,9 +805,13 @@ impl Response {
         match self.status {
             200 => "OK",
             201 => "CREATED",
+            301 => "MOVED PERMANENTLY",
+            302 => "FOUND",
+            303 => "SEE OTHER",
             401 => "UNAUTHORIZED",
             403 => "FORBIDDEN",
             404 => "NOT FOUND",
+            500 => "INTERNAL SERVER ERROR",
             _ => "NOT OK",
         }
     }
@@ -522,7 +856,9 @@ impl From<&[u8]> for Response {
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::server::ServerConfig;
 
     #[test]
     fn parse_headers() {
@@ -543,8 +879,7 @@ Sec-Fetch-Site: none
 Sec-Fetch-User: ?1
 
 ");
-        let mut lines = request_str.lines();
-        while let Some(buf) = lines.next() {
+        for buf in request_str.lines() {
             if buf == "\r\n" {
                 break;
             };
@@ -558,4 +893,68 @@ Sec-Fetch-User: ?1
         }
         println!("{:#?}", headers)
     }
+
+    #[test]
+    fn delete_needs_acl() {
+        let private_dir = std::env::temp_dir().join(format!("hello_server_acl_{}", std::process::id()));
+        std::fs::create_dir_all(&private_dir).unwrap();
+        // no rules, as shipped
+        std::fs::write(private_dir.join(".acl"), "// /  all  rwd\n").unwrap();
+        let mut s_conf = ServerConfig::new();
+        s_conf.private_dir = private_dir.display().to_string();
+        let previous = crate::VHOST_CONF.with(|current| current.replace(Some(Arc::new(s_conf))));
+
+        let mut request = super::Request::new();
+        request.method = super::RequestMethod::Delete;
+        request.url = "/static/hello.html".to_owned();
+        assert_eq!(request.authorize(&[]).map_err(|response| response.status), Err(403));
+        request.method = super::RequestMethod::Get;
+        assert!(request.authorize(&[]).is_ok());
+
+        // only the login needs no session, not everything at its paths
+        let session = [super::AuthScheme::Session];
+        request.url = "/static/style.css".to_owned();
+        assert!(request.authorize(&session).is_ok());
+        request.method = super::RequestMethod::Delete;
+        assert!(request.authorize(&session).is_err());
+        request.method = super::RequestMethod::Post;
+        assert!(request.authorize(&session).is_err());
+        request.url = "/login".to_owned();
+        assert!(request.authorize(&session).is_ok());
+
+        crate::VHOST_CONF.with(|current| current.replace(previous));
+        std::fs::remove_dir_all(&private_dir).unwrap();
+    }
+
+    #[test]
+    fn read_form_only() {
+        let body = "--x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nabc\r\n--x--\r\n";
+        let mut buf = super::SafeBuf::build(body.as_bytes()).unwrap();
+        let mut request = super::Request::build(super::RequestMethod::Post, "/login", b"");
+        request.headers.insert("Content-Type".to_owned(), "multipart/form-data; boundary=x".to_owned());
+        request.headers.insert("Content-Length".to_owned(), body.len().to_string());
+        assert_eq!(request.read_form(&mut buf).map_err(|response| response.status), Err(415));
+
+        let mut buf = super::SafeBuf::build(&b"username=alice&password=secret"[..]).unwrap();
+        request.headers.insert("Content-Type".to_owned(), "application/x-www-form-urlencoded".to_owned());
+        request.headers.insert("Content-Length".to_owned(), "30".to_owned());
+        assert!(request.read_form(&mut buf).is_ok());
+        assert_eq!(request.body, b"username=alice&password=secret");
+    }
+
+    #[test]
+    fn csrf_field_before_files() {
+        let denied = |body: &str| {
+            let mut buf = super::SafeBuf::build(body.as_bytes()).unwrap();
+            let mut request = super::Request::build(super::RequestMethod::Post, "/upload", b"");
+            request.headers.insert("Content-Type".to_owned(), "multipart/form-data; boundary=x".to_owned());
+            request.csrf_token = Some("abc".to_owned());
+            request.csrf_pending = true;
+            let e = request.read_body(&mut buf).unwrap_err();
+            e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+        };
+        let file = "Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\nabc\r\n";
+        assert!(denied(&format!("--x\r\n{}--x--\r\n", file)));
+        assert!(denied(&format!("--x\r\nContent-Disposition: form-data; name=\"csrf\"\r\n\r\nabd\r\n--x\r\n{}--x--\r\n", file)));
+    }
 }
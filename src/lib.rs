@@ -1,42 +1,333 @@
-use std::{thread::{JoinHandle, self}, sync::{mpsc::{self, Sender, Receiver}, Arc, Mutex}, fmt::Display};
+use std::{thread::{JoinHandle, self}, sync::{mpsc::{self, Sender, Receiver, TryRecvError, RecvTimeoutError}, Arc, Mutex, PoisonError, Condvar, atomic::{AtomicUsize, Ordering}}, fmt::Display, panic::{self, AssertUnwindSafe}, any::Any, marker::PhantomData, time::Duration, io};
+
+type WorkerHandle = Arc<Mutex<Option<JoinHandle<()>>>>;
+
+/// State shared by the pool and all of its worker threads.
+struct Shared {
+    receiver: Mutex<Receiver<Job>>,
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    min_threads: usize,
+    max_threads: usize,
+    keep_alive: Duration,
+    // live worker threads, those of them waiting for a job and jobs not picked up yet
+    total: AtomicUsize,
+    idle: AtomicUsize,
+    queued: AtomicUsize,
+}
 
 struct Worker {
     id: usize,
-    thread: Option<JoinHandle<()>>,
+    thread: WorkerHandle,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    // println!("Wokrer {id} got a job; executing.");
-                    job()
-                },
-                Err(_) => {
-                    // println!("Wokrer {id} disconnected; shutting down.");
-                    break
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let thread = Arc::new(Mutex::new(None));
+        Self::spawn(id, shared, thread.clone())?;
+        Ok(Worker {
+            id,
+            thread,
+        })
+    }
+    fn spawn(id: usize, shared: Arc<Shared>, handle: WorkerHandle) -> io::Result<()> {
+        let mut builder = thread::Builder::new();
+        if let Some(name_prefix) = &shared.name_prefix {
+            builder = builder.name(format!("{}-{}", name_prefix, id));
+        }
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let sentinel = Sentinel {
+            id,
+            shared: shared.clone(),
+            handle: handle.clone(),
+        };
+        let thread = builder.spawn(move || {
+            let _sentinel = sentinel;
+            loop {
+                shared.idle.fetch_add(1, Ordering::SeqCst);
+                let (message, shrunk) = {
+                    // a panicking job never holds the lock, but don't let a poisoned
+                    // mutex take every other worker down with it
+                    let receiver = shared.receiver.lock().unwrap_or_else(PoisonError::into_inner);
+                    let mut message = if shared.max_threads > shared.min_threads {
+                        receiver.recv_timeout(shared.keep_alive)
+                    } else {
+                        receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                    };
+                    shared.idle.fetch_sub(1, Ordering::SeqCst);
+                    let mut shrunk = false;
+                    if let Err(RecvTimeoutError::Timeout) = message {
+                        // idle for keep_alive, give the thread back unless we're at min size
+                        let min_threads = shared.min_threads;
+                        shrunk = shared.total.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+                            if total > min_threads { Some(total - 1) } else { None }
+                        }).is_ok();
+                        // a job sent since the timeout counted us as idle and didn't grow the lane
+                        if shrunk {
+                            if let Ok(job) = receiver.try_recv() {
+                                shared.total.fetch_add(1, Ordering::SeqCst);
+                                shrunk = false;
+                                message = Ok(job);
+                            }
+                        }
+                    }
+                    (message, shrunk)
+                };
+                match message {
+                    Ok(job) => {
+                        shared.queued.fetch_sub(1, Ordering::SeqCst);
+                        // println!("Wokrer {id} got a job; executing.");
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                            println!("ERROR: worker {} job panicked: {}", id, panic_message(&*payload));
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        if shrunk {
+                            break
+                        }
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // println!("Wokrer {id} disconnected; shutting down.");
+                        shared.total.fetch_sub(1, Ordering::SeqCst);
+                        break
+                    }
                 }
             }
-        });
-        Worker {
-            id, 
-            thread: Some(thread),
+        })?;
+        *handle.lock().unwrap_or_else(PoisonError::into_inner) = Some(thread);
+        Ok(())
+    }
+    fn is_finished(&self) -> bool {
+        match &*self.thread.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some(thread) => thread.is_finished(),
+            None => true,
+        }
+    }
+}
+
+/// Lives on the worker thread and respawns it if the thread unwinds
+/// past `catch_unwind`, so the pool never silently loses capacity.
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+    handle: WorkerHandle,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("ERROR: worker {} died; respawning", self.id);
+            if let Err(e) = Worker::spawn(self.id, self.shared.clone(), self.handle.clone()) {
+                self.shared.total.fetch_sub(1, Ordering::SeqCst);
+                println!("ERROR: worker {} respawn failed: {}", self.id, e);
+            }
         }
     }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// Name of the lane jobs go to unless `ThreadPool::execute_in` says otherwise.
+pub const DEFAULT_LANE: &str = "default";
+
+/// Best effort text of a panic payload, for logging.
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(str) = payload.downcast_ref::<&str>() {
+        str.to_string()
+    } else if let Some(string) = payload.downcast_ref::<String>() {
+        string.to_owned()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+/// Handle to a job started with `ThreadPool::spawn`.
+pub struct TaskHandle<T> {
+    receiver: Receiver<thread::Result<T>>,
+}
+
+impl<T> TaskHandle<T> {
+    /// Blocks until the job has run. `Err` holds the panic payload, or a
+    /// message if the pool shut down before the job was picked up.
+    pub fn join(self) -> thread::Result<T> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Box::new("task dropped before completion")),
+        }
+    }
+    /// Like `join`, but gives the handle back if the job hasn't finished yet.
+    pub fn try_join(self) -> Result<thread::Result<T>, Self> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(Box::new("task dropped before completion"))),
+        }
+    }
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        while *pending > 0 {
+            pending = self.done.wait(pending).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Decrements the scope counter when a scoped job finishes or is dropped unrun.
+struct ScopeJobGuard(Arc<ScopeState>);
+
+impl Drop for ScopeJobGuard {
+    fn drop(&mut self) {
+        let mut pending = self.0.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
+
+/// Jobs started through a `Scope` may borrow anything that outlives the
+/// `ThreadPool::scope` call.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub fn execute<F>(&'scope self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        let guard = ScopeJobGuard(self.state.clone());
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                guard.0.panic.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert(payload);
+            }
+            drop(guard);
+        });
+        // SAFETY: `ThreadPool::scope` doesn't return before every job counted in
+        // `pending` has run or been dropped, so nothing borrowed for 'scope is
+        // touched after it ends.
+        let job: Job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.send(job);
+    }
+}
+
+/// Workers fed from one queue. A pool has a default lane plus any extra
+/// lanes added with `ThreadPoolBuilder::lane`.
+struct Lane {
+    name: String,
+    workers: Mutex<Vec<Worker>>,
+    sender: Option<Sender<Job>>,
+    shared: Arc<Shared>,
+}
+
+impl Lane {
+    fn build(name: &str, min_threads: usize, max_threads: usize, thread_name: Option<String>, builder: &ThreadPoolBuilder) -> Result<Lane, PoolCreationError> {
+        if max_threads == 0 {
+            return Err(PoolCreationError::ZeroSize)
+        };
+        if min_threads > max_threads {
+            return Err(PoolCreationError::ExcessSize((min_threads, max_threads)))
+        };
+        if let Some(cap) = builder.cap {
+            if max_threads > cap {
+                return Err(PoolCreationError::ExcessSize((max_threads, cap)))
+            };
+        };
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            name_prefix: thread_name,
+            stack_size: builder.stack_size,
+            min_threads,
+            max_threads,
+            keep_alive: builder.keep_alive,
+            total: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        });
+        let mut workers = Vec::with_capacity(min_threads);
+        for id in 0..min_threads {
+            shared.total.fetch_add(1, Ordering::SeqCst);
+            workers.push(Worker::new(id, shared.clone()).map_err(PoolCreationError::Spawn)?)
+        }
+        Ok(Lane {
+            name: name.to_owned(),
+            workers: Mutex::new(workers),
+            sender: Some(sender),
+            shared,
+        })
+    }
+    fn grow(&self) {
+        let shared = &self.shared;
+        if shared.queued.load(Ordering::SeqCst) <= shared.idle.load(Ordering::SeqCst) {
+            return;
+        }
+        let max_threads = shared.max_threads;
+        let grown = shared.total.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+            if total < max_threads { Some(total + 1) } else { None }
+        });
+        if grown.is_err() {
+            return;
+        }
+        let mut workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+        // reuse the slot of a worker that shrank away
+        let result = match workers.iter().find(|worker| worker.is_finished()) {
+            Some(worker) => Worker::spawn(worker.id, shared.clone(), worker.thread.clone()),
+            None => Worker::new(workers.len(), shared.clone()).map(|worker| workers.push(worker)),
+        };
+        if let Err(e) = result {
+            shared.total.fetch_sub(1, Ordering::SeqCst);
+            println!("ERROR: can't grow thread pool lane {}: {}", self.name, e);
+        }
+    }
+    fn send(&self, job: Job) {
+        self.shared.queued.fetch_add(1, Ordering::SeqCst);
+        self.sender.as_ref().unwrap().send(job).unwrap();
+        if self.shared.max_threads > self.shared.min_threads {
+            self.grow();
+        }
+    }
+    fn join(&mut self) {
+        let workers = self.workers.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for worker in workers.iter_mut() {
+            println!("Shutting down worker {} {}", self.name, worker.id);
+            // a dying worker may put its replacement into the slot while we join
+            loop {
+                let thread = worker.thread.lock().unwrap_or_else(PoisonError::into_inner).take();
+                match thread {
+                    Some(thread) => {
+                        let _ = thread.join();
+                    },
+                    None => break
+                }
+            }
+        }
+    }
+}
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<Sender<Job>>, 
+    // lanes[0] is the default lane
+    lanes: Vec<Lane>,
 }
 
 pub enum PoolCreationError {
     ZeroSize,
     ExcessSize((usize, usize)),
+    Spawn(io::Error),
 }
 
 impl Display for PoolCreationError {
@@ -48,52 +339,292 @@ impl Display for PoolCreationError {
             PoolCreationError::ExcessSize(size) => {
                 format!("excrss pool size: {} max is {}", size.0, size.1)
             },            
+            PoolCreationError::Spawn(e) => {
+                format!("can't spawn worker thread: {}", e)
+            },
         };        
         write!(f, "{}", str)
     }
 }
 
-impl ThreadPool {
-    fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);       
-        for id in 0..size {
-            workers.push(Worker::new(id, receiver.clone()))
+/// Configures a `ThreadPool`. Unlike `ThreadPool::build` the size isn't
+/// capped by the CPU count unless asked to, since workers mostly wait on I/O.
+///
+/// With `max_threads` above `min_threads` the pool starts `min_threads`
+/// workers, adds one whenever jobs queue up with no idle worker, and lets extra
+/// workers exit after `keep_alive` without work.
+///
+/// Extra lanes get their own queue and workers, so a flood of slow jobs in
+/// one lane can't hold up the others.
+pub struct ThreadPoolBuilder {
+    min_threads: usize,
+    max_threads: Option<usize>,
+    lanes: Vec<(String, usize, usize)>,
+    cap: Option<usize>,
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    keep_alive: Duration,
+}
+
+impl ThreadPoolBuilder {
+    pub fn new() -> Self {
+        ThreadPoolBuilder {
+            min_threads: 1,
+            max_threads: None,
+            lanes: Vec::new(),
+            cap: None,
+            name_prefix: None,
+            stack_size: None,
+            keep_alive: Duration::from_secs(60),
         }
-        ThreadPool{
-            workers,
-            sender: Some(sender),
+    }
+    /// Fixed pool size, same as setting min and max to `size`.
+    pub fn size(mut self, size: usize) -> Self {
+        self.min_threads = size;
+        self.max_threads = Some(size);
+        self
+    }
+    pub fn min_threads(mut self, min_threads: usize) -> Self {
+        self.min_threads = min_threads;
+        self
+    }
+    /// Upper bound for dynamic growth, defaults to `min_threads`.
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+    /// Adds a lane with its own workers, see `ThreadPool::execute_in`.
+    pub fn lane(mut self, name: &str, min_threads: usize, max_threads: usize) -> Self {
+        self.lanes.push((name.to_owned(), min_threads, max_threads));
+        self
+    }
+    /// Refuse to build when a lane's max_threads exceeds `cap`; no cap by default.
+    pub fn cap(mut self, cap: Option<usize>) -> Self {
+        self.cap = cap;
+        self
+    }
+    /// Threads get named `{name_prefix}-{id}`, or `{name_prefix}-{lane}-{id}`
+    /// outside the default lane.
+    pub fn name_prefix(mut self, name_prefix: &str) -> Self {
+        self.name_prefix = Some(name_prefix.to_owned());
+        self
+    }
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = Some(stack_size);
+        self
+    }
+    /// How long a worker above `min_threads` waits for a job before exiting.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let max_threads = self.max_threads.unwrap_or(self.min_threads);
+        let mut lanes = Vec::with_capacity(self.lanes.len() + 1);
+        lanes.push(Lane::build(DEFAULT_LANE, self.min_threads, max_threads, self.name_prefix.clone(), &self)?);
+        for (name, min_threads, max_threads) in &self.lanes {
+            let thread_name = self.name_prefix.as_ref().map(|prefix| format!("{}-{}", prefix, name));
+            lanes.push(Lane::build(name, *min_threads, *max_threads, thread_name, &self)?);
         }
+        Ok(ThreadPool { lanes })
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThreadPool {
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
     }
     pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError>{
-        if size == 0 {
-            return Err(PoolCreationError::ZeroSize)    
-        };
         let max_size = thread::available_parallelism().unwrap().get();
-        if size > max_size {
-            return Err(PoolCreationError::ExcessSize((size, max_size)))    
-        };         
-        Ok(Self::new(size))
+        Self::builder().size(size).cap(Some(max_size)).build()
+    }
+    /// Number of live worker threads across all lanes.
+    pub fn thread_count(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.shared.total.load(Ordering::SeqCst)).sum()
+    }
+    /// Number of live worker threads in `lane`, `None` if there's no such lane.
+    pub fn lane_thread_count(&self, lane: &str) -> Option<usize> {
+        self.lanes.iter()
+            .find(|item| item.name == lane)
+            .map(|lane| lane.shared.total.load(Ordering::SeqCst))
+    }
+    fn send(&self, job: Job) {
+        self.lanes[0].send(job);
     }
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();     
+        self.send(Box::new(f));
+    }
+    /// Runs `f` in the named lane, or in the default lane if the pool has no
+    /// such lane.
+    pub fn execute_in<F>(&self, lane: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let lane = self.lanes.iter().find(|item| item.name == lane).unwrap_or(&self.lanes[0]);
+        lane.send(Box::new(f));
+    }
+    /// Runs `f` on the pool and returns a handle to its result.
+    pub fn spawn<F, T>(&self, f: F) -> TaskHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // the handle may be gone already, nobody wants the result then
+            let _ = sender.send(result);
+        });
+        TaskHandle { receiver }
+    }
+    /// Runs `f` with a `Scope` whose jobs may borrow local data, and waits for
+    /// all of them before returning. A panic in any job is resumed here once
+    /// every job is done. Calling this from a job of the same pool can
+    /// deadlock when all workers are busy.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                panic: Mutex::new(None),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+        let job_panic = scope.state.panic.lock().unwrap_or_else(PoisonError::into_inner).take();
+        match (result, job_panic) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(value), None) => value,
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap()
+        for lane in &mut self.lanes {
+            drop(lane.sender.take());
+        }
+        for lane in &mut self.lanes {
+            lane.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::{mpsc, Arc, Barrier}, thread, time::{Duration, Instant}};
+
+    use super::ThreadPool;
+
+    #[test]
+    fn panicking_job_keeps_worker() {
+        let pool = ThreadPool::build(1).unwrap_or_else(|e| panic!("{}", e));
+        pool.execute(|| panic!("job panic"));
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(42).unwrap());
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+
+    #[test]
+    fn spawn_join() {
+        let pool = ThreadPool::build(1).unwrap_or_else(|e| panic!("{}", e));
+        let handle = pool.spawn(|| 2 + 2);
+        assert_eq!(handle.join().unwrap(), 4);
+        let handle = pool.spawn(|| -> usize { panic!("task panic") });
+        let payload = handle.join().unwrap_err();
+        assert_eq!(super::panic_message(&*payload), "task panic");
+    }
+
+    #[test]
+    fn scope_borrows() {
+        let pool = ThreadPool::build(1).unwrap_or_else(|e| panic!("{}", e));
+        let mut numbers = vec![1, 2, 3];
+        let total = std::sync::Mutex::new(0);
+        pool.scope(|scope| {
+            for number in &mut numbers {
+                let total = &total;
+                scope.execute(move || {
+                    *number *= 10;
+                    *total.lock().unwrap() += *number;
+                });
             }
+        });
+        assert_eq!(numbers, vec![10, 20, 30]);
+        assert_eq!(*total.lock().unwrap(), 60);
+    }
+
+    #[test]
+    fn builder_grows_and_shrinks() {
+        let pool = ThreadPool::builder()
+            .min_threads(1)
+            .max_threads(4)
+            .name_prefix("test-worker")
+            .keep_alive(Duration::from_millis(50))
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e));
+        let (sender, receiver) = mpsc::channel();
+        let barrier = Arc::new(Barrier::new(4));
+        for _ in 0..4 {
+            let sender = sender.clone();
+            let barrier = barrier.clone();
+            pool.execute(move || {
+                barrier.wait();
+                sender.send(thread::current().name().map(str::to_owned)).unwrap();
+            });
         }
+        for _ in 0..4 {
+            assert!(receiver.recv().unwrap().unwrap().starts_with("test-worker-"));
+        }
+        assert_eq!(pool.thread_count(), 4);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while pool.thread_count() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.thread_count(), 1);
+    }
+
+    #[test]
+    fn builder_sizes() {
+        assert!(ThreadPool::builder().size(0).build().is_err());
+        assert!(ThreadPool::builder().min_threads(3).max_threads(2).build().is_err());
+        assert!(ThreadPool::builder().size(8).cap(Some(4)).build().is_err());
+        let pool = ThreadPool::builder().size(8).build().unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(pool.thread_count(), 8);
+    }
+
+    #[test]
+    fn busy_lane_doesnt_block_default() {
+        let pool = ThreadPool::builder()
+            .size(1)
+            .lane("bulk", 1, 1)
+            .name_prefix("test-worker")
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(pool.lane_thread_count("bulk"), Some(1));
+        assert_eq!(pool.thread_count(), 2);
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute_in("bulk", move || {
+            let _ = blocked.recv();
+        });
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(thread::current().name().map(str::to_owned)).unwrap());
+        let name = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(name.as_deref(), Some("test-worker-0"));
+        release.send(()).unwrap();
     }
 }
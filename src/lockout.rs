@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::server::AuthError;
+
+/// The one answer to a failed login, whether the user exists or not.
+pub const FAILED_MSG: &str = "wrong username or password";
+// failures before the backoff starts
+const IP_FREE_FAILURES: u32 = 5;
+// higher than per address, someone on the guest network shouldn't lock
+// the admin out with a handful of guesses
+const USER_FREE_FAILURES: u32 = 10;
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+// a counter without failures for this long starts over
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+const MAX_ENTRIES: usize = 100_000;
+
+// "ip <address>" or "user <name>", "user <name>@<vhost>" on virtual hosts,
+// -> failures
+static FAILURES: OnceLock<Mutex<HashMap<String, Failures>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// How long after the last failure further attempts are refused, doubling
+    /// with every failure past the free ones.
+    fn lockout(&self, free: u32) -> Duration {
+        if self.count < free {
+            return Duration::ZERO;
+        }
+        let doublings = (self.count - free).min(16);
+        (Duration::from_secs(1) * 2u32.pow(doublings)).min(MAX_LOCKOUT)
+    }
+    fn locked(&self, free: u32) -> bool {
+        self.last.elapsed() < self.lockout(free)
+    }
+}
+
+fn failures() -> MutexGuard<'static, HashMap<String, Failures>> {
+    FAILURES.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn keys(ip: Option<IpAddr>, username: &str) -> Vec<(String, u32)> {
+    let mut keys = Vec::with_capacity(2);
+    if let Some(ip) = ip {
+        keys.push((format!("ip {}", ip), IP_FREE_FAILURES));
+    }
+    if !username.is_empty() {
+        // same name, different user on another virtual host
+        let vhost = crate::s_conf().map(|s_conf| s_conf.vhost.clone()).unwrap_or_default();
+        let key = if vhost.is_empty() { format!("user {}", username) } else { format!("user {}@{}", username, vhost) };
+        keys.push((key, USER_FREE_FAILURES));
+    }
+    keys
+}
+
+/// Runs `login` unless the client address or the username is locked out,
+/// and counts it against both when it's denied. Locked out attempts get the
+/// same answer as wrong passwords and aren't checked at all.
+pub fn attempt(ip: Option<IpAddr>, username: &str, login: impl FnOnce() -> Result<(), AuthError>) -> Result<(), AuthError> {
+    let keys = keys(ip, username);
+    {
+        let failures = failures();
+        let locked = keys.iter().any(|(key, free)| failures.get(key).is_some_and(|failures| failures.locked(*free)));
+        if locked {
+            return Err(AuthError::Denied(FAILED_MSG.to_string()));
+        }
+    }
+    match login() {
+        Ok(()) => {
+            // the address keeps its count, a valid account mustn't reset it between guesses
+            if let Some((key, _)) = keys.iter().find(|(key, _)| key.starts_with("user ")) {
+                failures().remove(key);
+            }
+            Ok(())
+        },
+        Err(AuthError::Denied(_)) => {
+            record(&keys);
+            Err(AuthError::Denied(FAILED_MSG.to_string()))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn record(keys: &[(String, u32)]) {
+    let mut failures = failures();
+    if failures.len() >= MAX_ENTRIES {
+        failures.retain(|_, failures| failures.last.elapsed() < FORGET_AFTER);
+    }
+    if failures.len() >= MAX_ENTRIES {
+        if let Some(oldest) = failures.iter().min_by_key(|(_, failures)| failures.last).map(|(key, _)| key.clone()) {
+            failures.remove(&oldest);
+        }
+    }
+    let now = Instant::now();
+    for (key, free) in keys {
+        let entry = failures.entry(key.clone()).or_insert(Failures { count: 0, last: now });
+        if entry.last.elapsed() >= FORGET_AFTER {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        let lockout = entry.lockout(*free);
+        if !lockout.is_zero() {
+            println!("login lockout: {} for {}s after {} failed logins", key, lockout.as_secs(), entry.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{attempt, AuthError, FAILED_MSG, IP_FREE_FAILURES};
+
+    #[test]
+    fn locks_out_after_failures() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)));
+        let denied = || Err(AuthError::Denied("password is incorrect".to_string()));
+        for _ in 0..IP_FREE_FAILURES {
+            assert_eq!(attempt(ip, "lockout_test", denied), Err(AuthError::Denied(FAILED_MSG.to_string())));
+        }
+        // the right password doesn't help while locked out
+        let mut checked = false;
+        assert!(attempt(ip, "lockout_test", || { checked = true; Ok(()) }).is_err());
+        assert!(!checked);
+        assert!(attempt(None, "other_user", || Ok(())).is_ok());
+    }
+}
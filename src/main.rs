@@ -1,96 +1,501 @@
 pub mod fs_html;
+mod acl;
+mod config;
+mod digest;
+mod forward;
 mod http;
+mod init;
+mod lockout;
+mod passwd;
+mod reactor;
 mod server;
+mod session;
+mod tls;
+mod token;
+mod totp;
+mod users;
 
-use hello_server::ThreadPool;
-use http::{Request, Response};
-use server::ServerConfig;
+use hello_server::{ThreadPool, panic_message, DEFAULT_LANE};
+use http::{Peer, Request, Response, SafeBuf, SharedStream};
+use server::{AuthScheme, IoMode, ServerConfig};
+use socket2::{Domain, Socket, Type};
+use users::UserStore;
+use acl::{AclStore, Permission};
 use std::{
     env::{self},
-    io::Write,
-    net::{TcpListener, TcpStream},
-    time::Duration, collections::HashMap, sync::{Mutex, OnceLock},
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener},
+    time::Duration, collections::HashMap, sync::{Arc, Mutex, OnceLock, PoisonError, RwLock},
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
 };
 
-static S_CACHE: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+// swapped as a whole when the config is reloaded
+static S_CONF: OnceLock<RwLock<Arc<ServerConfig>>> = OnceLock::new();
 
-static S_CONF: OnceLock<ServerConfig> = OnceLock::new();
+// what the server was started with, read again by a reload
+static S_CLI: OnceLock<config::Cli> = OnceLock::new();
+
+// .htpasswd, .htdigest, .tokens and .totp by path, every private_dir in
+// use has its own
+static S_STORES: OnceLock<Mutex<HashMap<String, Arc<UserStore>>>> = OnceLock::new();
+
+// .acl by path, likewise
+static S_ACLS: OnceLock<Mutex<HashMap<String, Arc<AclStore>>>> = OnceLock::new();
+
+thread_local! {
+    // settings of the virtual host whose request this thread is handling
+    static VHOST_CONF: RefCell<Option<Arc<ServerConfig>>> = const { RefCell::new(None) };
+}
+
+// uploads and big downloads run here so they can't starve page loads
+const BULK_LANE: &str = "bulk";
 
 fn main() {
-    init_folders();
-    let mut args = env::args();
-    args.next(); //command line
-    if let Some(arg) = args.next() {
-        if arg.contains("help") || arg.contains("-h") {
-            print_help(&arg);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(arg) = args.first() {
+        let sub_args = args[1..].iter().cloned();
+        if arg == "init" {
+            if let Err(e) = init::run(sub_args) {
+                println!("init error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        if arg == "config" {
+            if let Err(e) = config::run(sub_args) {
+                println!("config error: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        // the files they edit are in the configured private_dir
+        if arg == "token" || arg == "user" {
+            let mut sub_args: Vec<String> = sub_args.collect();
+            match subcommand_config(&mut sub_args) {
+                Ok(s_conf) => S_CONF.set(RwLock::new(Arc::new(s_conf))).unwrap(),
+                Err(e) => {
+                    println!("config error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            let result = if arg == "token" { token::run(sub_args.into_iter()) } else { users::run(sub_args.into_iter()) };
+            if let Err(e) = result {
+                println!("{} error: {}", arg, e);
+                std::process::exit(1);
+            }
             return;
         }
     };
-    // let s_conf = Arc::new(ServerConfig::from_args(env::args()));
-    let mut s_conf = ServerConfig::from_config_file();
-    s_conf.update_from_args(env::args());
+    let cli = match config::Cli::parse(args.into_iter()) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("{}, see --help", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.help {
+        print_help();
+        return;
+    }
+    if cli.version {
+        println!("hello_server {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    // command line over environment over config file
+    let s_conf = match config::resolve(&cli, env::vars_os()) {
+        Ok((val, _)) => val,
+        Err(e) => {
+            println!("config error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    init_folders(&s_conf);
 
     // println!("{:#?}", s_conf);
 
-    let address = format!("0.0.0.0:{}", s_conf.port);
-    let listener = TcpListener::bind(address).unwrap();
+    let listeners = s_conf.listeners();
+    let mut sockets = Vec::with_capacity(listeners.len());
+    for listener in &listeners {
+        // [::] takes IPv4 as well, unless IPv4 has a listener of its own on the port
+        let v6_only = listener.address.is_ipv6()
+            && listeners.iter().any(|other| other.address.is_ipv4() && other.address.port() == listener.address.port());
+        match bind(listener.address, v6_only) {
+            Ok(val) => sockets.push(val),
+            Err(e) => {
+                println!("can't listen on {}: {}", listener.address, e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let pool = match ThreadPool::build(s_conf.thread_count) {
-        Ok(v) => v,
+    let mut pool_builder = ThreadPool::builder()
+        .min_threads(s_conf.thread_count)
+        .max_threads(s_conf.max_threads.max(s_conf.thread_count))
+        .name_prefix("hello-worker");
+    if s_conf.thread_stack_size > 0 {
+        pool_builder = pool_builder.stack_size(s_conf.thread_stack_size);
+    }
+    if s_conf.bulk_threads > 0 {
+        pool_builder = pool_builder.lane(
+            BULK_LANE,
+            s_conf.bulk_threads,
+            s_conf.bulk_max_threads.max(s_conf.bulk_threads),
+        );
+    }
+    let pool = match pool_builder.build() {
+        Ok(v) => Arc::new(v),
         Err(e) => {
             println!("error creating pool thread: {}", e);
-            return;
+            std::process::exit(1);
         }
     };
 
-    S_CONF.set(s_conf).unwrap();
-    S_CACHE.set(Mutex::new(HashMap::new())).unwrap();
+    if let Err(e) = check_config(&s_conf) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+    let any_tls = listeners.iter().any(|listener| listener.tls_files(&s_conf).is_some());
+    let client_ca = Some(s_conf.tls_client_ca.as_str()).filter(|path| !path.is_empty());
+    // with other schemes next to mtls clients may come without a certificate
+    let require_client_cert = s_conf.auth_schemes == [AuthScheme::Mtls];
+    let mut tls_configs = Vec::with_capacity(listeners.len());
+    for listener in &listeners {
+        let tls_config = match listener.tls_files(&s_conf) {
+            Some(("", _)) | Some((_, "")) => {
+                println!("listener {} is https but has no tls_cert and tls_key", listener.address);
+                std::process::exit(1);
+            },
+            Some((cert, key)) => match tls::server_config(cert, key, client_ca, require_client_cert) {
+                Ok(val) => Some(val),
+                Err(e) => {
+                    println!("error loading tls certificate: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        println!("listening on {}://{}", if tls_config.is_some() { "https" } else { "http" }, listener.address);
+        tls_configs.push(tls_config);
+    }
+    let https_port = listeners.iter().find(|listener| listener.tls_files(&s_conf).is_some()).map(|listener| listener.address.port() as usize);
+    if let Some(https_port) = https_port.filter(|_| s_conf.https_redirect_port > 0) {
+        spawn_https_redirect(SocketAddr::new(s_conf.bind, s_conf.https_redirect_port as u16), https_port, pool.clone());
+    }
+    let io_mode = match s_conf.io_mode {
+        IoMode::Event if any_tls => {
+            println!("event io mode doesn't support tls, using threads");
+            IoMode::Threads
+        },
+        io_mode => io_mode,
+    };
+    S_CONF.set(RwLock::new(Arc::new(s_conf))).unwrap();
+    S_CLI.set(cli).unwrap();
+    #[cfg(unix)]
+    spawn_reload_on_sighup();
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        stream
-            .set_read_timeout(Some(Duration::from_secs(5)))
-            .unwrap();
-        // let s_conf_ref = s_conf.clone();
-        pool.execute(|| handle_connection(stream));
+    if let IoMode::Event = io_mode {
+        let sockets = sockets.into_iter().zip(listeners.iter().map(|listener| listener.admin)).collect();
+        match reactor::Reactor::build(sockets, pool).and_then(|mut reactor| reactor.run()) {
+            Ok(_) => {},
+            Err(e) => {
+                println!("ERROR: reactor\n{}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    // every listener gets a thread accepting for the one pool
+    let accept_threads: Vec<_> = sockets.into_iter().zip(tls_configs).zip(&listeners)
+        .map(|((socket, tls_config), listener)| {
+            let pool = pool.clone();
+            let admin = listener.admin;
+            std::thread::spawn(move || accept_connections(socket, tls_config, admin, pool))
+        })
+        .collect();
+    for accept_thread in accept_threads {
+        let _ = accept_thread.join();
     }
 
     println!("main end");
 
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let auth_scheme = crate::S_CONF.get().unwrap().auth_scheme();
-    
-    // println!("\nhandle_connection {}", stream.local_addr().unwrap());
-    let mut request = match http::Request::try_from(&stream) {
+/// A listening socket on `address`. IPv6 ones take IPv4 clients as well
+/// unless `v6_only`, whatever the system default is.
+fn bind(address: SocketAddr, v6_only: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // like std, a restart doesn't wait for old connections in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+fn accept_connections(listener: TcpListener, tls_config: Option<Arc<rustls::ServerConfig>>, admin: bool, pool: Arc<ThreadPool>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(val) => val,
+            Err(e) => {
+                println!("ERROR: listener.accept\n{}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(5))) {
+            println!("ERROR: TcpStream::set_read_timeout\n{}", e);
+            continue;
+        }
+        let pool_ref = pool.clone();
+        let stream = match &tls_config {
+            Some(tls_config) => match tls::TlsStream::accept(tls_config, stream) {
+                Ok(val) => SharedStream::new(val),
+                Err(e) => {
+                    println!("ERROR: TlsStream::accept\n{}", e);
+                    continue;
+                }
+            },
+            None => SharedStream::new(stream),
+        };
+        pool.execute(move || serve(stream, |stream| handle_connection(stream, admin, &pool_ref)));
+    }
+}
+
+/// The config for the `token` and `user` subcommands, that of the virtual
+/// host named by a `--vhost <HOST>` in `args`, which is taken out.
+fn subcommand_config(args: &mut Vec<String>) -> Result<ServerConfig, String> {
+    let (s_conf, _) = config::resolve(&Default::default(), env::vars_os())?;
+    let index = match args.iter().position(|arg| arg == "--vhost") {
+        Some(val) => val,
+        None => return Ok(s_conf),
+    };
+    let vhost = args.get(index + 1).ok_or("--vhost needs a host name")?.to_lowercase();
+    args.drain(index..index + 2);
+    let vhost_conf = s_conf.vhosts.get(&vhost).ok_or_else(|| format!("no [vhosts.\"{}\"] in the config", vhost))?;
+    Ok((**vhost_conf).clone())
+}
+
+/// What the settings need to work together, checked before they're used.
+fn check_config(s_conf: &ServerConfig) -> Result<(), String> {
+    let any_tls = s_conf.listeners().iter().any(|listener| listener.tls_files(s_conf).is_some());
+    if s_conf.auth_schemes.contains(&AuthScheme::Mtls) && (!any_tls || s_conf.tls_client_ca.is_empty()) {
+        return Err("auth mtls needs tls_cert, tls_key and tls_client_ca".to_string());
+    }
+    if s_conf.auth_schemes.contains(&AuthScheme::Forward) && !s_conf.forward_auth_url.starts_with("http://") {
+        return Err("auth forward needs an http:// forward_auth_url".to_string());
+    }
+    for mount in &s_conf.mounts {
+        if !std::path::Path::new(&mount.path).is_dir() {
+            return Err(format!("mount {}: \"{}\" isn't a directory", mount.prefix, mount.path));
+        }
+    }
+    for (name, vhost_conf) in &s_conf.vhosts {
+        check_config(vhost_conf).map_err(|e| format!("vhost {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// The settings in effect, those of the virtual host while one of its
+/// requests is handled. A reload swaps them, whoever holds the old ones
+/// finishes with them.
+pub fn s_conf() -> Option<Arc<ServerConfig>> {
+    VHOST_CONF.with(|vhost_conf| vhost_conf.borrow().clone())
+        .or_else(|| S_CONF.get().map(|s_conf| s_conf.read().unwrap_or_else(PoisonError::into_inner).clone()))
+}
+
+/// The settings for the Host header of `request`, the default ones for
+/// hosts without a [vhosts] entry.
+pub fn vhost_conf(request: &Request) -> Option<Arc<ServerConfig>> {
+    let s_conf = S_CONF.get()?.read().unwrap_or_else(PoisonError::into_inner).clone();
+    let host = request.headers.get("Host").map_or("", |str| str).to_lowercase();
+    // without the port, IPv6 brackets stay
+    let host = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host.as_str(),
+    };
+    Some(s_conf.vhosts.get(host).cloned().unwrap_or(s_conf))
+}
+
+/// While it lives, `s_conf()` on this thread answers with the settings of
+/// the virtual host the request is for, the same ones even if a reload
+/// happens meanwhile.
+struct VhostScope(Option<Arc<ServerConfig>>);
+
+impl VhostScope {
+    fn enter(request: &Request) -> Self {
+        let vhost_conf = vhost_conf(request);
+        VhostScope(VHOST_CONF.with(|current| current.replace(vhost_conf)))
+    }
+}
+
+impl Drop for VhostScope {
+    fn drop(&mut self) {
+        VHOST_CONF.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+/// `name` in the current private_dir, a user store kept for the server's
+/// lifetime so its cache is too.
+pub fn user_store(name: &str) -> Arc<UserStore> {
+    let path = private_path(name);
+    let mut stores = S_STORES.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    stores.entry(path).or_insert_with_key(|path| Arc::new(UserStore::new(path))).clone()
+}
+
+/// The ACL of the current private_dir.
+pub fn acl_store() -> Arc<AclStore> {
+    let path = private_path(acl::ACL_FILE);
+    let mut acls = S_ACLS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    acls.entry(path).or_insert_with_key(|path| Arc::new(AclStore::new(path))).clone()
+}
+
+/// Reads the config again the way the server was started, for SIGHUP and
+/// `POST /admin/reload`. Settings the running server can't change keep
+/// their values until a restart, which is logged.
+fn reload_config() -> Result<String, String> {
+    let cli = S_CLI.get().ok_or("S_CLI uninitialized")?;
+    let old = s_conf().ok_or("S_CONF uninitialized")?;
+    let (new, _) = config::resolve(cli, env::vars_os())?;
+    let (new, changed, restart) = config::reload(&old, new);
+    check_config(&new)?;
+    init_folders(&new);
+    let mut report = if changed.is_empty() {
+        "config reloaded, nothing changed".to_string()
+    } else {
+        format!("config reloaded, changed: {}", changed.join(", "))
+    };
+    if !restart.is_empty() {
+        report.push_str(&format!("\nneeds a restart, running with the old value until then: {}", restart.join(", ")));
+    }
+    // the TLS handshake asks for client certificates as decided at startup
+    if (old.auth_schemes == [AuthScheme::Mtls]) != (new.auth_schemes == [AuthScheme::Mtls]) {
+        report.push_str("\nwhether TLS clients must send a certificate changes with a restart");
+    }
+    if let Some(s_conf) = S_CONF.get() {
+        *s_conf.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(new);
+    }
+    println!("{}", report);
+    Ok(report)
+}
+
+/// Reloads the config on every SIGHUP.
+#[cfg(unix)]
+fn spawn_reload_on_sighup() {
+    let mut signals = match signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP]) {
         Ok(val) => val,
         Err(e) => {
-            let error_str = format!("Request::try_from\n{}", e);
-            println!("ERROR: {}", error_str);
-            let response = Response::from(&error_str[..]);
-            match stream.write_all(&response.as_bytes()) {
-                Ok(_) => {},
-                Err(e) => println!("stream.write_all\n{}", e)
-            };
+            println!("ERROR: SIGHUP handler\n{}", e);
             return;
         }
     };
-    if let Err(response) = request.authorize(auth_scheme) {
-        match stream.write_all(&response.as_bytes()) {
-            Ok(_) => {},
-            Err(e) => println!("stream.write_all\n{}", e)
-        };        
-        return;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if let Err(e) = reload_config() {
+                println!("ERROR: config reload, keeping the old settings\n{}", e);
+            }
+        }
+    });
+}
+
+/// `POST /admin/reload`, only on admin listeners.
+fn response_admin_reload() -> Response {
+    match reload_config() {
+        Ok(report) => Response::from(report.as_str()),
+        Err(e) => {
+            let error_str = format!("config reload, keeping the old settings\n{}", e);
+            println!("ERROR: {}", error_str);
+            let mut response = Response::from(error_str.as_str());
+            response.status = 500;
+            response
+        },
     }
-    // println!("{:#?}", request);
-    let response = match request.method {
-        http::RequestMethod::Get => response_get(&mut request),
-        http::RequestMethod::Post => Response::from(&request.body[..]),
-        _ => http::Response::build_request_echo(&request),
+}
+
+/// Listens for plain http on `address` and points every request to the
+/// same url over https on `https_port`.
+fn spawn_https_redirect(address: SocketAddr, https_port: usize, pool: Arc<ThreadPool>) {
+    let listener = match TcpListener::bind(address) {
+        Ok(val) => val,
+        Err(e) => {
+            println!("ERROR: https redirect listener\n{}", e);
+            return;
+        }
     };
-    // println!("{:#?}", response);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(val) => val,
+                Err(e) => {
+                    println!("ERROR: https redirect accept\n{}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(5))) {
+                println!("ERROR: TcpStream::set_read_timeout\n{}", e);
+                continue;
+            }
+            let stream = SharedStream::new(stream);
+            pool.execute(move || serve(stream, |stream| redirect_to_https(stream, https_port)));
+        }
+    });
+}
+
+fn redirect_to_https(stream: &SharedStream, https_port: usize) {
+    let request = match SafeBuf::build(stream.clone()).and_then(|mut buf| Request::read_head(&mut buf)) {
+        Ok(val) => val,
+        Err(e) => return write_error(stream, "Request::read_head", &e),
+    };
+    let host = request.headers.get("Host").map_or("localhost", |str| str);
+    let mut response = Response::from("");
+    response.status = 301;
+    response.headers.insert("Location".to_owned(), https_location(host, &request.target, https_port));
+    write_response(stream, &response);
+}
 
+fn https_location(host: &str, target: &str, https_port: usize) -> String {
+    // drop the port of the plain http listener, keep IPv6 brackets
+    let host = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[..pos],
+        _ => host,
+    };
+    if https_port == 443 {
+        format!("https://{}{}", host, target)
+    } else {
+        format!("https://{}:{}{}", host, https_port, target)
+    }
+}
+
+/// Runs `handler` on the connection, answering 500 if it panics.
+fn serve<F: FnOnce(&SharedStream)>(stream: SharedStream, handler: F) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| handler(&stream)));
+    if let Err(payload) = result {
+        println!("ERROR: handle_connection panicked: {}", panic_message(&*payload));
+        write_response(&stream, &internal_error());
+    }
+}
+
+/// Like `serve` for a response that is built first and written elsewhere.
+fn respond_or_500<F: FnOnce() -> Response>(f: F) -> Response {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(response) => response,
+        Err(payload) => {
+            println!("ERROR: handle_connection panicked: {}", panic_message(&*payload));
+            internal_error()
+        }
+    }
+}
+
+fn internal_error() -> Response {
+    let mut response = Response::from("internal server error");
+    response.status = 500;
+    response
+}
+
+fn write_response(mut stream: &SharedStream, response: &Response) {
     match stream.write_all(&response.as_bytes()) {
         Ok(_) => {}
         Err(e) => {
@@ -105,108 +510,278 @@ fn handle_connection(mut stream: TcpStream) {
     };
 }
 
-fn response_get(request: &mut Request) -> http::Response {
-    match request.url.as_str() {
-        "/" => Response::from_file("./public/static/hello.html"),
-        "/upload" => Response::from_file("./public/static/upload.html"),
-        "/favicon.ico" => Response::from_file("./public/static/favicon.ico"),
-        "/echo" => http::Response::build_request_echo(request),
-        _ => {
-            let mut files_html = fs_html::FilesHtml::new(&request.url);
-            Response::from(files_html.response_body().as_slice())
-        } 
+fn write_error(stream: &SharedStream, context: &str, e: &dyn std::fmt::Display) {
+    let error_str = format!("{}\n{}", context, e);
+    println!("ERROR: {}", error_str);
+    write_response(stream, &Response::from(&error_str[..]));
+}
+
+fn handle_connection(stream: &SharedStream, admin: bool, pool: &Arc<ThreadPool>) {
+    // println!("\nhandle_connection {}", stream.local_addr().unwrap());
+    let mut buf = match SafeBuf::build(stream.clone()) {
+        Ok(val) => val,
+        Err(e) => return write_error(stream, "SafeBuf::build", &e),
+    };
+    let request = match read_request_head(&mut buf, || Peer { admin, ..stream.peer() }) {
+        Ok(val) => val,
+        Err(response) => return write_response(stream, &response),
+    };
+    let lane = lane_for(&request);
+    if lane == DEFAULT_LANE {
+        finish_connection(stream, request, buf);
+        return;
     }
+    let stream = stream.clone();
+    pool.execute_in(lane, move || serve(stream, move |stream| finish_connection(stream, request, buf)));
 }
 
-fn print_help(arg: &str) {
-    match arg {
-        "help" | "-help" | "--help" | "-h" | "--h" => {
-            println!("hello server [OPTIONS]");
-            println!("OPTIONS:");
-            println!(" -a, --auth <basic|none>  default is none");
-            println!(" -t, --threads <NUMBER>   default is 2");
-            println!(" -p, --port <NUMBER>   default is 8080");
-            // println!(" -e, --encryption <ecb|cbc>");
+/// Reads and authorizes the request head, before anything of the body gets
+/// written to disk. `peer` tells about the client once the head is read,
+/// for tls that's after the handshake.
+fn read_request_head<R: Read>(buf: &mut SafeBuf<R>, peer: impl FnOnce() -> Peer) -> Result<Request, Response> {
+    let mut request = match Request::read_head(buf) {
+        Ok(val) => val,
+        Err(e) => {
+            let error_str = format!("Request::read_head\n{}", e);
+            println!("ERROR: {}", error_str);
+            return Err(Response::from(&error_str[..]));
         }
-        _ => {}
+    };
+    request.peer = peer();
+    let _vhost = VhostScope::enter(&request);
+    let s_conf = s_conf().unwrap();
+    request.authorize(s_conf.auth_schemes())?;
+    let (path, permission) = request.access();
+    if let Some(mount) = s_conf.mount_for(path).filter(|mount| !mount.permits(permission)) {
+        let refused = if permission == Permission::Write { "uploads" } else { "deletes" };
+        let mut response = Response::from(&format!("{} are turned off for {}", refused, mount.prefix)[..]);
+        response.status = 403;
+        return Err(response);
     }
+    Ok(request)
 }
 
-fn init_folders() {
-    let path = std::path::Path::new("./public/content/");
-    if !path.exists() {
-        std::fs::create_dir(path).unwrap();
+/// Answers a request that arrives whole through `buf`.
+fn respond_buffered<R: Read>(mut buf: SafeBuf<R>, peer: impl FnOnce() -> Peer) -> Response {
+    match read_request_head(&mut buf, peer) {
+        Ok(request) => respond(request, &mut buf),
+        Err(response) => response,
     }
-    let path = std::path::Path::new("./public/content/upload");
-    if !path.exists() {
-        std::fs::create_dir(path).unwrap();
-    }    
 }
 
-fn get_from_cache(name: &str) -> Option<String> {
-    let mutex = match S_CACHE.get() {
-        Some(val) => {
-            val
+/// Picks the pool lane for the rest of the request, judging from its head.
+fn lane_for(request: &Request) -> &'static str {
+    let _vhost = VhostScope::enter(request);
+    let threshold = s_conf().unwrap().bulk_threshold as u64;
+    match request.method {
+        http::RequestMethod::Post => BULK_LANE,
+        http::RequestMethod::Get => {
+            let file_len = std::fs::metadata(root_path(&request.url))
+                .map_or(0, |metadata| if metadata.is_file() { metadata.len() } else { 0 });
+            if file_len > threshold {
+                BULK_LANE
+            } else {
+                DEFAULT_LANE
+            }
         },
-        None => {
-            panic!("S_CACHE uninitialized");
-        }
-    };
-    let hash_map = match mutex.lock() {
-        Ok(val) => val,
-        Err(e) => {
-            println!("get_from_cache/mutex.lock()\n{}", e);
-            return None
-        }
-    };
-    // println!("cache get\n{:#?}", hash_map);
-    match hash_map.get(name) {
-        Some(val) => Some(val.to_owned()),
-        None => None
+        _ => DEFAULT_LANE,
     }
 }
 
-fn _update_in_cache(name: &str, value: &str) {
-    let mutex = match S_CACHE.get() {
-        Some(val) => {
-            val
-        },
-        None => {
-            panic!("S_CACHE uninitialized");
+fn finish_connection<R: Read>(stream: &SharedStream, request: Request, mut buf: SafeBuf<R>) {
+    write_response(stream, &respond(request, &mut buf));
+}
+
+/// Reads the body and routes the request.
+fn respond<R: Read>(mut request: Request, buf: &mut SafeBuf<R>) -> Response {
+    let _vhost = VhostScope::enter(&request);
+    // takes no body, which would otherwise be an upload
+    if request.peer.admin && matches!(request.method, http::RequestMethod::Post) && request.url == "/admin/reload" {
+        return response_admin_reload();
+    }
+    // the login takes no session, so nothing but its small form gets read
+    if matches!(request.method, http::RequestMethod::Post) && session::is_public(&request) {
+        if let Err(response) = request.read_form(buf) {
+            return response;
         }
-    };
-    let mut hash_map = match mutex.lock() {
-        Ok(val) => val,
-        Err(e) => {
-            println!("get_from_cache/mutex.lock()\n{}", e);
-            return
+        return match request.url.as_str() {
+            "/login/totp" => session::login_totp(&request),
+            _ => session::login(&request),
+        };
+    }
+    if let Err(e) = request.read_body(buf) {
+        let error_str = format!("Request::read_body\n{}", e);
+        println!("ERROR: {}", error_str);
+        let mut response = Response::from(&error_str[..]);
+        if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied) {
+            response.status = 403;
         }
+        return response;
+    }
+    // println!("{:#?}", request);
+    let response = match (&request.method, request.url.as_str()) {
+        (_, "/logout") => session::logout(&request),
+        _ => match request.method {
+            http::RequestMethod::Get => response_get(&mut request),
+            http::RequestMethod::Post => Response::from(&request.body[..]),
+            http::RequestMethod::Delete => response_delete(&request),
+            _ => http::Response::build_request_echo(&request),
+        },
     };
-    hash_map.insert(name.to_owned(), value.to_owned());
-    // println!("cache update\n{:#?}", hash_map);
+    // println!("{:#?}", response);
+    response
 }
 
-fn append_to_cache(name: &str, value: &str) {
-    let mutex = match S_CACHE.get() {
-        Some(val) => {
-            val
+fn response_get(request: &mut Request) -> http::Response {
+    match request.url.as_str() {
+        "/" => Response::from_file(&root_path("/static/hello.html")),
+        "/upload" => match std::fs::read_to_string(root_path("/static/upload.html")) {
+            Ok(upload_html) => {
+                let csrf_token = request.csrf_token.as_deref().unwrap_or_default();
+                Response::from(upload_html.replace("{csrf}", csrf_token).as_str())
+            },
+            Err(e) => {
+                println!("ERROR: upload.html\n{}", e);
+                internal_error()
+            },
         },
-        None => {
-            panic!("S_CACHE uninitialized");
+        "/login" => Response::from_file(&root_path("/static/login.html")),
+        "/login/totp" => Response::from_file(&root_path("/static/totp.html")),
+        "/favicon.ico" => Response::from_file(&root_path("/static/favicon.ico")),
+        "/echo" => http::Response::build_request_echo(request),
+        _ => {
+            let mut files_html = fs_html::FilesHtml::new(&request.url);
+            files_html.csrf_token = request.csrf_token.clone();
+            Response::from(files_html.response_body().as_slice())
+        } 
+    }
+}
+
+/// Removes a file, or a directory once it's empty.
+fn response_delete(request: &Request) -> Response {
+    let s_conf = s_conf();
+    let mount_point = |path: &str| s_conf.as_ref().is_some_and(|s_conf| s_conf.mounts.iter().any(|mount| mount.prefix == path));
+    let path = match acl::normalize(&request.url) {
+        Some(val) if val != "/" && !mount_point(&val) => root_path(&val),
+        _ => {
+            let mut response = Response::from("can't delete that");
+            response.status = 403;
+            return response;
         }
     };
-    let mut hash_map = match mutex.lock() {
-        Ok(val) => val,
+    let result = match std::fs::metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir(&path),
+        Ok(_) => std::fs::remove_file(&path),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => Response::from("deleted"),
         Err(e) => {
-            println!("get_from_cache/mutex.lock()\n{}", e);
-            return
+            let mut response = Response::from(&format!("{}: \"{}\"", e, request.url)[..]);
+            response.status = if e.kind() == std::io::ErrorKind::NotFound { 404 } else { 500 };
+            response
         }
+    }
+}
+
+fn print_help() {
+    println!("hello server [OPTIONS]");
+    println!("hello server init [--lan] [--san <NAME|IP>]... [--force] [--config <PATH>]");
+    println!("  create a self-signed certificate and .htpasswd in the private_dir, config.toml where --config reads it");
+    println!("hello server user <add|passwd|digest> <NAME> [--stdin]");
+    println!("hello server user totp <NAME> [--remove]");
+    println!("  two-factor login with an authenticator app, for --auth session, prints the setup URI and recovery codes");
+    println!("hello server user remove <NAME>");
+    println!("hello server user list");
+    println!("  edit the users in ./private/.htpasswd, passwords are stored as bcrypt hashes");
+    println!("  digest stores credentials for --auth digest in ./private/.htdigest, kept in step from then on");
+    println!("hello server config migrate [--force]");
+    println!("  turn an old ./private/.config into ./private/config.toml");
+    println!("hello server config show [OPTIONS]");
+    println!("  print the effective settings and where each one comes from");
+    println!("hello server token create <USER> [--scope <rwd>] [--prefix <PATH>] [--expires <N>d|<N>h|never]");
+    println!("hello server token revoke <ID>");
+    println!("hello server token list");
+    println!("  api tokens for --auth bearer, only their hashes are kept in ./private/.tokens");
+    println!("  user and token take --vhost <HOST> for the files in the private_dir of that virtual host");
+    println!("per-path read/write/delete permissions go in ./private/.acl");
+    println!("SIGHUP, or POST /admin/reload on a listener with admin = true, reloads the config");
+    println!("virtual hosts with their own root, users and auth go in [vhosts.\"<HOST>\"] tables of config.toml");
+    println!("OPTIONS:");
+    println!("  every option is also a key of config.toml and an environment variable, HELLO_SERVER_FILE_SIZE_LIMIT");
+    println!("  for --file-size-limit; the command line wins over the environment, which wins over the file and its vhosts");
+    println!("  --name=value works as well");
+    println!(" -c, --config <PATH>   default is HELLO_SERVER_CONFIG or ./private/config.toml, which may be missing");
+    println!("  default paths, like ./private/config.toml, are relative to the current directory");
+    println!(" -p, --port <NUMBER>   default is 8080");
+    println!(" -b, --bind <IP>   address to listen on, default is 0.0.0.0");
+    println!(" -l, --listen <[http://|https://]IP:PORT>[,...]   several addresses instead of --bind and --port");
+    println!(" -r, --root <PATH>   the served folder, default is ./public");
+    println!("     --mounts </PREFIX=PATH>[,...]   more folders under url prefixes, see config.toml for read-only ones");
+    println!("     --private-dir <PATH>   users, tokens and the ACL, default is ./private");
+    println!(" -a, --auth <basic|digest|mtls|bearer|session|forward|none>[,...]  default is none");
+    println!("     session logs browsers in with a form at /login, /logout ends the session");
+    println!("     forward asks the gateway at --forward-auth-url about every request");
+    println!("     --realm <NAME>   protection space of basic and digest auth, default is hello_server");
+    println!("     --plaintext-passwords <true|false>   accept unhashed .htpasswd entries");
+    println!("     --forward-auth-url <URL>   http:// endpoint of the auth gateway");
+    println!("     --forward-auth-user-header <NAME>   default is Remote-User");
+    println!("     --io <threads|event>   event multiplexes idle connections, default is threads");
+    println!(" -t, --threads <NUMBER>   default is 1");
+    println!("     --max-threads <NUMBER>   grow the pool up to this under load");
+    println!("     --thread-stack-size <SIZE>   0 keeps the system default");
+    println!("     --bulk-threads <NUMBER>   workers for uploads and big downloads, default is 1");
+    println!("     --bulk-max-threads <NUMBER>");
+    println!("     --bulk-threshold <SIZE>   default is 1MiB");
+    println!("     --tls-cert <PATH>   PEM certificate chain, serve https with --tls-key");
+    println!("     --tls-key <PATH>   PEM private key");
+    println!("     --tls-client-ca <PATH>   PEM CA for client certificates, needed by --auth mtls");
+    println!("     --redirect-port <NUMBER>   plain http port redirecting to https");
+    println!("     --buf-string-limit <SIZE>");
+    println!("     --file-buf-size-limit <SIZE>");
+    println!("     --file-size-limit <SIZE>   largest upload");
+    println!("  SIZE is bytes or a number with k, m or g");
+    println!(" -V, --version");
+    println!(" -h, --help");
+}
+
+fn init_folders(s_conf: &ServerConfig) {
+    let roots = std::iter::once(&s_conf.root).chain(s_conf.vhosts.values().map(|vhost_conf| &vhost_conf.root));
+    for root in roots {
+        let path = format!("{}/content/upload", root.trim_end_matches('/'));
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            println!("ERROR: create_dir_all\n{}: \"{}\"", e, path);
+        }
+    }
+}
+
+/// Where the url `path` is on disk, in the mount it's below or else in
+/// the root. Paths climbing out of it end at the root.
+pub fn root_path(path: &str) -> String {
+    let s_conf = s_conf();
+    let path = acl::normalize(path).unwrap_or_else(|| "/".to_owned());
+    let (dir, rest) = match s_conf.as_ref().and_then(|s_conf| s_conf.mount_for(&path)) {
+        Some(mount) => (mount.path.as_str(), &path[mount.prefix.len()..]),
+        None => (s_conf.as_ref().map_or("./public", |s_conf| s_conf.root.as_str()), path.as_str()),
     };
-    let mut init_value = match hash_map.get(name) {
-        Some(val) => val.to_owned(),
-        None => String::new()
-    };
-    init_value.push_str(value);
-    hash_map.insert(name.to_owned(), init_value);
-    // println!("cache append\n{:#?}", hash_map);
+    format!("{}{}", dir.trim_end_matches('/'), rest)
+}
+
+/// `name` in the private_dir, ./private before a config is loaded.
+pub fn private_path(name: &str) -> String {
+    let s_conf = s_conf();
+    let private_dir = s_conf.as_ref().map_or("./private", |s_conf| s_conf.private_dir.as_str());
+    format!("{}/{}", private_dir.trim_end_matches('/'), name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::https_location;
+
+    #[test]
+    fn https_redirect_location() {
+        assert_eq!(https_location("example.org:8080", "/content/a%20b?x=1", 8443), "https://example.org:8443/content/a%20b?x=1");
+        assert_eq!(https_location("[::1]:8080", "/", 443), "https://[::1]/");
+        assert_eq!(https_location("[::1]", "/", 443), "https://[::1]/");
+    }
 }
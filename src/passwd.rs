@@ -0,0 +1,152 @@
+use md5::{Digest, Md5};
+use pwhash::{bcrypt, sha256_crypt, sha512_crypt};
+use ring::rand::{SecureRandom, SystemRandom};
+use subtle::ConstantTimeEq;
+
+const APR1_MAGIC: &str = "$apr1$";
+const CRYPT_HASH64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Checks a password against the second field of an `.htpasswd` line, in the
+/// formats `htpasswd` writes: bcrypt (`-B`), SHA-256/512-crypt (`-5`, `-2`)
+/// and Apache MD5 (`-m`, the default). Anything not starting with `$` or `{`
+/// is a plaintext password and only accepted with `allow_plaintext`.
+pub fn verify(password: &str, stored: &str, allow_plaintext: bool) -> bool {
+    if stored.starts_with("$2a$") || stored.starts_with("$2b$") || stored.starts_with("$2y$") {
+        bcrypt::verify(password, stored)
+    } else if stored.starts_with("$6$") {
+        sha512_crypt::verify(password, stored)
+    } else if stored.starts_with("$5$") {
+        sha256_crypt::verify(password, stored)
+    } else if let Some(rest) = stored.strip_prefix(APR1_MAGIC) {
+        match rest.split_once('$') {
+            Some((salt, _)) => consteq(&apr1(password.as_bytes(), salt.as_bytes()), stored),
+            None => false,
+        }
+    } else if !stored.starts_with('$') && !stored.starts_with('{') {
+        if !allow_plaintext {
+            println!("plaintext password in .htpasswd rejected, hash it with htpasswd or set plaintext_passwords = true");
+            return false;
+        }
+        consteq(password, stored)
+    } else {
+        // {SHA}, crypt() and other formats too weak to be worth supporting
+        false
+    }
+}
+
+/// bcrypt hash for new passwords, the same as `htpasswd -B` writes.
+pub fn hash(password: &str) -> Result<String, String> {
+    // bcrypt ignores everything after that
+    if password.len() > 72 {
+        return Err("password longer than 72 bytes".to_string());
+    }
+    bcrypt::hash(password).map_err(|e| e.to_string())
+}
+
+pub fn consteq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && bool::from(a.as_bytes().ct_eq(b.as_bytes()))
+}
+
+/// Hex string of `len` random bytes, for nonces and tokens.
+pub fn random_hex(len: usize) -> String {
+    random_bytes(len).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        // ring only fails without an OS random source, nothing works then
+        panic!("no random source available");
+    }
+    bytes
+}
+
+/// Apache's variant of md5-crypt, the same algorithm with its own magic.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+    let mut alternate = Md5::new();
+    alternate.update(password);
+    alternate.update(salt);
+    alternate.update(password);
+    let alternate = alternate.finalize();
+
+    let mut digest = Md5::new();
+    digest.update(password);
+    digest.update(APR1_MAGIC.as_bytes());
+    digest.update(salt);
+    for chunk in password.chunks(16) {
+        digest.update(&alternate[..chunk.len()]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 == 1 {
+            digest.update([0u8]);
+        } else {
+            digest.update(&password[..1]);
+        }
+        len >>= 1;
+    }
+    let mut hash = digest.finalize();
+
+    for round in 0..1000 {
+        let mut digest = Md5::new();
+        if round % 2 == 1 {
+            digest.update(password);
+        } else {
+            digest.update(hash);
+        }
+        if round % 3 != 0 {
+            digest.update(salt);
+        }
+        if round % 7 != 0 {
+            digest.update(password);
+        }
+        if round % 2 == 1 {
+            digest.update(hash);
+        } else {
+            digest.update(password);
+        }
+        hash = digest.finalize();
+    }
+
+    let mut encoded = String::with_capacity(22);
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        let value = (hash[a] as u32) << 16 | (hash[b] as u32) << 8 | hash[c] as u32;
+        push_hash64(&mut encoded, value, 4);
+    }
+    push_hash64(&mut encoded, hash[11] as u32, 2);
+    format!("{}{}${}", APR1_MAGIC, String::from_utf8_lossy(salt), encoded)
+}
+
+fn push_hash64(out: &mut String, mut value: u32, chars: usize) {
+    for _ in 0..chars {
+        out.push(CRYPT_HASH64[(value & 0x3f) as usize] as char);
+        value >>= 6;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify;
+
+    #[test]
+    fn apache_hashes() {
+        let apr1 = "$apr1$r31..G7H$KUQHIBVdT/wwf4Hzc2wWw.";
+        assert!(verify("password1", apr1, false));
+        assert!(!verify("password2", apr1, false));
+        let sha512 = "$6$saltsalt$rGHbrrsOT1WLTt4dcfZKq1FiG//1B7ZAMkD.MeAC8/d9MOtB5EzYEffFnBarQhF6MiLywY/KggaYjrNNrzAnj/";
+        assert!(verify("password1", sha512, false));
+        assert!(!verify("password2", sha512, false));
+        let bcrypt = pwhash::bcrypt::hash("password1").unwrap();
+        assert!(verify("password1", &bcrypt, false));
+        assert!(!verify("password2", &bcrypt, false));
+    }
+
+    #[test]
+    fn plaintext_needs_flag() {
+        assert!(!verify("password1", "password1", false));
+        assert!(verify("password1", "password1", true));
+        assert!(!verify("password2", "password1", true));
+        assert!(!verify("password1", "{SHA}password1", true));
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Write},
+    net,
+    sync::{mpsc::{self, Receiver, Sender}, Arc},
+    time::{Duration, Instant},
+};
+
+use hello_server::{ThreadPool, DEFAULT_LANE};
+use mio::{net::{TcpListener, TcpStream}, Events, Interest, Poll, Token, Waker};
+
+use crate::http::{Peer, Request, RequestMethod, Response, SafeBuf, SharedStream};
+
+const WAKER: Token = Token(0);
+// the listeners come right after the waker, then the connections
+const READ_CHUNK_SIZE: usize = 8192;
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// idle sockets only cost a table entry here, so they may wait longer
+// than the read timeout of the threaded mode
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+enum State {
+    Reading,
+    Processing,
+    Writing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // request bytes while reading, response bytes while writing
+    data: Vec<u8>,
+    written: usize,
+    state: State,
+    last_activity: Instant,
+    // accepted on an admin listener
+    admin: bool,
+}
+
+/// Serves connections from a single thread with mio, so idle and slow
+/// clients cost a socket rather than a worker. A request is read until it's
+/// complete, processed on the pool, and its response handed back here to be
+/// written out. Uploads bigger than `bulk_threshold` go to the bulk lane
+/// together with their socket and are finished there in blocking mode.
+pub struct Reactor {
+    poll: Poll,
+    // with whether they're admin listeners
+    listeners: Vec<(TcpListener, bool)>,
+    waker: Arc<Waker>,
+    sender: Sender<(Token, Vec<u8>)>,
+    receiver: Receiver<(Token, Vec<u8>)>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    pool: Arc<ThreadPool>,
+}
+
+impl Reactor {
+    pub fn build(std_listeners: Vec<(net::TcpListener, bool)>, pool: Arc<ThreadPool>) -> io::Result<Reactor> {
+        let poll = Poll::new()?;
+        let mut listeners = Vec::with_capacity(std_listeners.len());
+        for (index, (listener, admin)) in std_listeners.into_iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            let mut listener = TcpListener::from_std(listener);
+            poll.registry().register(&mut listener, Token(WAKER.0 + 1 + index), Interest::READABLE)?;
+            listeners.push((listener, admin));
+        }
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = mpsc::channel();
+        Ok(Reactor {
+            poll,
+            next_token: WAKER.0 + 1 + listeners.len(),
+            listeners,
+            waker,
+            sender,
+            receiver,
+            connections: HashMap::new(),
+            pool,
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(Duration::from_secs(1))) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.collect_responses(),
+                    Token(index) if index <= self.listeners.len() => self.accept(index - 1),
+                    token => {
+                        if event.is_readable() {
+                            self.read(token);
+                        }
+                        if event.is_writable() {
+                            self.write(token);
+                        }
+                    }
+                }
+            }
+            self.close_idle();
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            let (listener, admin) = &self.listeners[index];
+            let admin = *admin;
+            let mut stream = match listener.accept() {
+                Ok((stream, _addr)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    println!("ERROR: listener.accept\n{}", e);
+                    return;
+                }
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                println!("ERROR: registry.register\n{}", e);
+                continue;
+            }
+            self.connections.insert(token, Connection {
+                stream,
+                data: Vec::new(),
+                written: 0,
+                state: State::Reading,
+                last_activity: Instant::now(),
+                admin,
+            });
+        }
+    }
+
+    fn read(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(val) => val,
+            None => return,
+        };
+        if !matches!(connection.state, State::Reading) {
+            return;
+        }
+        let mut chunk = [0u8; READ_CHUNK_SIZE];
+        let mut eof = false;
+        loop {
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => {
+                    // a client may shut down its side right after the request
+                    eof = true;
+                    break;
+                },
+                Ok(len) => {
+                    connection.data.extend_from_slice(&chunk[..len]);
+                    connection.last_activity = Instant::now();
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("ERROR: stream.read\n{}", e);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+        self.dispatch(token);
+        let incomplete = self.connections.get(&token).is_some_and(|connection| matches!(connection.state, State::Reading));
+        if eof && incomplete {
+            // closed before a whole request came in
+            self.close(token);
+        }
+    }
+
+    /// Hands the request to the pool once enough of it has arrived.
+    fn dispatch(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(val) => val,
+            None => return,
+        };
+        let head_end = match find(&connection.data, b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => {
+                if connection.data.len() > MAX_HEAD_SIZE {
+                    let response = Response::from("request head too large");
+                    self.respond(token, response.as_bytes());
+                }
+                return;
+            }
+        };
+        let request = match SafeBuf::build(Cursor::new(connection.data[..head_end].to_vec()))
+            .and_then(|mut buf| Request::read_head(&mut buf))
+        {
+            Ok(val) => val,
+            Err(e) => {
+                let error_str = format!("Request::read_head\n{}", e);
+                println!("ERROR: {}", error_str);
+                self.respond(token, Response::from(&error_str[..]).as_bytes());
+                return;
+            }
+        };
+        let threshold = crate::vhost_conf(&request).unwrap().bulk_threshold;
+        let content_length = request.headers.get("Content-Length").and_then(|value| value.parse::<usize>().ok());
+        match (&request.method, content_length) {
+            // wait for the rest of a small upload
+            (RequestMethod::Post, Some(len)) if len <= threshold && connection.data.len() < head_end + len => {
+                return;
+            },
+            (RequestMethod::Post, Some(len)) if len <= threshold => {},
+            (RequestMethod::Post, _) => {
+                self.hand_off(token);
+                return;
+            },
+            _ => {},
+        }
+        connection.state = State::Processing;
+        let data = std::mem::take(&mut connection.data);
+        let peer = Peer {
+            addr: connection.stream.peer_addr().ok().map(|addr| addr.ip()),
+            cert_name: None,
+            tls: false,
+            admin: connection.admin,
+        };
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        let job = move || {
+            let response = crate::respond_or_500(|| match SafeBuf::build(Cursor::new(data)) {
+                Ok(buf) => crate::respond_buffered(buf, || peer),
+                Err(e) => Response::from(&format!("SafeBuf::build\n{}", e)[..]),
+            });
+            // the reactor is gone only if the server is shutting down
+            if sender.send((token, response.as_bytes())).is_ok() {
+                if let Err(e) = waker.wake() {
+                    println!("ERROR: waker.wake\n{}", e);
+                }
+            }
+        };
+        // the lane may depend on the file size, a slow disk mustn't stall
+        // this thread, so a worker finds out
+        let pool = self.pool.clone();
+        self.pool.execute(move || match crate::lane_for(&request) {
+            DEFAULT_LANE => job(),
+            lane => pool.execute_in(lane, job),
+        });
+    }
+
+    /// Gives a big upload its socket back in blocking mode and lets a bulk
+    /// worker read the rest of it.
+    fn hand_off(&mut self, token: Token) {
+        let mut connection = match self.connections.remove(&token) {
+            Some(val) => val,
+            None => return,
+        };
+        if let Err(e) = self.poll.registry().deregister(&mut connection.stream) {
+            println!("ERROR: registry.deregister\n{}", e);
+        }
+        let stream: net::TcpStream = connection.stream.into();
+        if let Err(e) = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(Duration::from_secs(5))))
+        {
+            println!("ERROR: TcpStream::set_nonblocking\n{}", e);
+            return;
+        }
+        let data = connection.data;
+        let admin = connection.admin;
+        self.pool.execute_in(crate::BULK_LANE, move || {
+            crate::serve(SharedStream::new(stream), |stream| {
+                let reader = Cursor::new(data).chain(stream.clone());
+                match SafeBuf::build(reader) {
+                    Ok(buf) => crate::write_response(stream, &crate::respond_buffered(buf, || Peer { admin, ..stream.peer() })),
+                    Err(e) => crate::write_error(stream, "SafeBuf::build", &e),
+                }
+            })
+        });
+    }
+
+    fn collect_responses(&mut self) {
+        while let Ok((token, data)) = self.receiver.try_recv() {
+            self.respond(token, data);
+        }
+    }
+
+    fn respond(&mut self, token: Token, data: Vec<u8>) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(val) => val,
+            None => return,
+        };
+        connection.data = data;
+        connection.written = 0;
+        connection.state = State::Writing;
+        connection.last_activity = Instant::now();
+        if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, Interest::WRITABLE) {
+            println!("ERROR: registry.reregister\n{}", e);
+            self.close(token);
+            return;
+        }
+        self.write(token);
+    }
+
+    fn write(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(val) => val,
+            None => return,
+        };
+        if !matches!(connection.state, State::Writing) {
+            return;
+        }
+        while connection.written < connection.data.len() {
+            match connection.stream.write(&connection.data[connection.written..]) {
+                Ok(0) => break,
+                Ok(len) => {
+                    connection.written += len;
+                    connection.last_activity = Instant::now();
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("stream.write\n{}", e);
+                    break;
+                }
+            }
+        }
+        // one request per connection, same as the threaded mode
+        self.close(token);
+    }
+
+    fn close_idle(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<Token> = self.connections.iter()
+            .filter(|(_, connection)| !matches!(connection.state, State::Processing))
+            .filter(|(_, connection)| now.duration_since(connection.last_activity) > IDLE_TIMEOUT)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            if let Err(e) = self.poll.registry().deregister(&mut connection.stream) {
+                println!("ERROR: registry.deregister\n{}", e);
+            }
+        }
+    }
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|window| window == pattern)
+}
+
+#[cfg(test)]
+mod test {
+    use super::find;
+
+    #[test]
+    fn find_head_end() {
+        assert_eq!(find(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody", b"\r\n\r\n"), Some(23));
+        assert_eq!(find(b"GET / HTTP/1.1\r\n", b"\r\n\r\n"), None);
+    }
+}
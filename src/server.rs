@@ -1,11 +1,23 @@
 
-use std::{fs, env::Args};
+use std::{collections::BTreeMap, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::{Arc, OnceLock}};
 
 use base64::{Engine, engine::general_purpose as b64};
 
-#[derive(Debug)]
+use crate::acl::Permission;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum AuthScheme {
     Basic,
+    // RFC 7616, for clients without TLS, credentials from ./private/.htdigest
+    Digest,
+    // TLS client certificate signed by tls_client_ca, the subject CN is the username
+    Mtls,
+    // API tokens from `hello_server token create`
+    Bearer,
+    // cookie from the /login form, for browsers
+    Session,
+    // an external gateway at forward_auth_url decides
+    Forward,
     None,
 }
 
@@ -14,28 +26,175 @@ impl TryFrom<&str> for AuthScheme {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value.to_lowercase().as_str() {
             "basic" => Ok(Self::Basic),
+            "digest" => Ok(Self::Digest),
+            "mtls" => Ok(Self::Mtls),
+            "bearer" => Ok(Self::Bearer),
+            "session" => Ok(Self::Session),
+            "forward" => Ok(Self::Forward),
             "none" => Ok(Self::None),
             str => Err(format!("{} auth scheme not implemented", str))
         }
     }
 }
 
+impl AuthScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Basic => "basic",
+            Self::Digest => "digest",
+            Self::Mtls => "mtls",
+            Self::Bearer => "bearer",
+            Self::Session => "session",
+            Self::Forward => "forward",
+            Self::None => "none",
+        }
+    }
+    /// Comma separated schemes a client may pick from, `none` alone or an
+    /// empty list leaves the server open.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, String> {
+        let mut schemes = Vec::new();
+        for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let scheme = Self::try_from(name)?;
+            if scheme != Self::None && !schemes.contains(&scheme) {
+                schemes.push(scheme);
+            }
+        }
+        Ok(schemes)
+    }
+}
+
+/// An address connections are accepted on. `tls` None serves https when
+/// tls_cert and tls_key are set, like the default listener on bind and port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    pub tls: Option<bool>,
+    // certificate of this listener only, tls_cert and tls_key when empty
+    pub tls_cert: String,
+    pub tls_key: String,
+    // answers /admin/ requests, meant for a localhost-only address
+    pub admin: bool,
+}
+
+impl Listener {
+    pub fn new(address: SocketAddr) -> Self {
+        Listener {
+            address,
+            tls: None,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            admin: false,
+        }
+    }
+    /// `127.0.0.1:8080`, `[::]:8443`, with `http://` or `https://` in front
+    /// to decide about TLS regardless of the global certificate.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (tls, address) = if let Some(address) = value.strip_prefix("https://") {
+            (Some(true), address)
+        } else if let Some(address) = value.strip_prefix("http://") {
+            (Some(false), address)
+        } else {
+            (None, value)
+        };
+        let address = address.trim_end_matches('/').parse()
+            .map_err(|_| format!("'{}' isn't an address like 127.0.0.1:8080 or [::]:8443", value))?;
+        Ok(Listener { tls, ..Listener::new(address) })
+    }
+    /// Certificate and key this listener serves https with, none for plain http.
+    pub fn tls_files<'a>(&'a self, s_conf: &'a ServerConfig) -> Option<(&'a str, &'a str)> {
+        let (cert, key) = if self.tls_cert.is_empty() && self.tls_key.is_empty() {
+            (s_conf.tls_cert.as_str(), s_conf.tls_key.as_str())
+        } else {
+            (self.tls_cert.as_str(), self.tls_key.as_str())
+        };
+        match self.tls {
+            Some(false) => None,
+            Some(true) => Some((cert, key)),
+            None => Some((cert, key)).filter(|(cert, key)| !cert.is_empty() && !key.is_empty()),
+        }
+    }
+}
+
+/// A directory served under a url prefix in place of what root has there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    // normalized, like "/datasets"
+    pub prefix: String,
+    pub path: String,
+    // neither uploads nor deletes
+    pub read_only: bool,
+    // new files may be uploaded, deletes are up to read_only
+    pub upload: bool,
+}
+
+impl Mount {
+    pub fn permits(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::Read => true,
+            Permission::Write => self.upload && !self.read_only,
+            Permission::Delete => !self.read_only,
+        }
+    }
+}
+
+/// How connections are served: a worker per connection, or a mio event
+/// loop that only hands complete requests to the workers.
+#[derive(Debug, Clone, Copy)]
+pub enum IoMode {
+    Threads,
+    Event,
+}
+
+impl IoMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Threads => "threads",
+            Self::Event => "event",
+        }
+    }
+}
+
+impl TryFrom<&str> for IoMode {
+    type Error = String;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "threads" => Ok(Self::Threads),
+            "event" => Ok(Self::Event),
+            str => Err(format!("{} io mode not implemented", str))
+        }
+    }
+}
+
+/// Wrong or missing credentials are the client's problem, a user store that
+/// can't be read is the server's.
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Denied(String),
+    // digest credentials were right, but the nonce expired
+    Stale,
+    Internal(String),
+}
+
 pub trait Auth {
-    fn authorize(&mut self) -> Result<(), String>;
+    fn authorize(&mut self) -> Result<(), AuthError>;
 }
 
 #[derive(Debug)]
 pub struct BasicAuth {
     credentials_str: String,
+    // failures are counted per client address too
+    ip: Option<IpAddr>,
     username: String,
     password: String,
 }
 
 impl BasicAuth {
-    pub fn new(str: &str) -> Self {
+    pub fn new(str: &str, ip: Option<IpAddr>) -> Self {
         let credentials_str = str.replace("Basic ", "");
         BasicAuth {
             credentials_str,
+            ip,
             username: String::from(""),
             password: String::from(""),
         }
@@ -58,236 +217,158 @@ impl BasicAuth {
         } else {
             return Err("no username provided".to_string())
         }.to_string();
-        self.password = if let Some(value) = split.next() {
-            value
-        } else {
-            ""
-        }.to_string();
+        self.password = split.next().unwrap_or_default().to_string();
         Ok(())
     }
-    fn validate_username(&self) -> Result<(), String> { 
-        let users_str = match crate::get_from_cache("htpasswd") {
-            Some(value) => value,
-            None => String::new()
-        };
-        let users_lines = users_str.lines();
-        for line in users_lines {
-            if line.contains(format!("{}:", self.username).as_str()) {
-                let mut line_split = line.split(':');
-                if line_split.next().is_none() {
-                    return Err("user not found".to_string())
-                };
-                if let Some(passwd) = line_split.next() {
-                    if passwd == self.password {
-                        return Ok(());
-                    } else {
-                        return Err("password is incorrect".to_string());
-                    }
-                };
-            };
-        };
-        let users_str = {
-            let file_path = "./private/.htpasswd";
-            let htpasswd = match std::fs::read_to_string(file_path) {
-                Ok(val) => val,
-                Err(e) => panic!("{}: \"{}\"", e, file_path)
-            };                
-            htpasswd
-        };
-        let users_lines = users_str.lines();
-        for line in users_lines {
-            if line.contains(format!("{}:", self.username).as_str()) {
-                let mut line_split = line.split(':');
-                if line_split.next().is_none() {
-                    return Err("user not found".to_string())
-                };
-                if let Some(passwd) = line_split.next() {
-                    crate::append_to_cache("htpasswd", format!("{}\r\n", line).as_str());
-                    if passwd == self.password {
-                        return Ok(());
-                    } else {
-                        return Err("password is incorrect".to_string());
-                    }
-                };
-            };
-        };            
-        Err("user not found".to_string())
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    fn validate_username(&self) -> Result<(), AuthError> {
+        check_password(&self.username, &self.password)
     }
 }
 
+/// Checks a username and password against ./private/.htpasswd. Unknown
+/// users take as long as wrong passwords, so timing doesn't tell them apart.
+pub fn check_password(username: &str, password: &str) -> Result<(), AuthError> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let users = crate::user_store(crate::users::HTPASSWD_FILE);
+    let stored = match users.password(username).map_err(AuthError::Internal)? {
+        Some(val) => val,
+        None => {
+            let dummy_hash = DUMMY_HASH.get_or_init(|| crate::passwd::hash("dummy").unwrap_or_default());
+            crate::passwd::verify(password, dummy_hash, false);
+            return Err(AuthError::Denied(crate::lockout::FAILED_MSG.to_string()));
+        }
+    };
+    if crate::passwd::verify(password, &stored, plaintext_allowed()) {
+        Ok(())
+    } else {
+        Err(AuthError::Denied(crate::lockout::FAILED_MSG.to_string()))
+    }
+}
+
+fn plaintext_allowed() -> bool {
+    crate::s_conf().is_some_and(|s_conf| s_conf.plaintext_passwords)
+}
+
 impl Auth for BasicAuth {
-    fn authorize(&mut self) -> Result<(), String> {
-        self.parse()?;
-        self.validate_username()
+    fn authorize(&mut self) -> Result<(), AuthError> {
+        self.parse().map_err(AuthError::Denied)?;
+        crate::lockout::attempt(self.ip, &self.username, || self.validate_username())?;
+        crate::totp::refuse_enrolled(&self.username)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ServerLimits {
     pub buf_string_limit: usize,
     pub file_buf_size_limit: usize,
     pub file_size_limit: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ServerConfig {
     // pub encryption: Option<Encryption>,
-    pub auth_scheme: AuthScheme,
+    // empty when no authentication is needed
+    pub auth_schemes: Vec<AuthScheme>,
+    pub io_mode: IoMode,
     pub thread_count: usize,
+    // upper bound for the pool growing under load, 0 means fixed at thread_count
+    pub max_threads: usize,
+    pub thread_stack_size: usize,
+    // separate workers for uploads and downloads above bulk_threshold bytes,
+    // 0 bulk_threads runs everything on the main workers
+    pub bulk_threads: usize,
+    pub bulk_max_threads: usize,
+    pub bulk_threshold: usize,
     pub port: usize,
+    // address to listen on, all of them by default
+    pub bind: IpAddr,
+    // every address to accept connections on, bind and port when empty
+    pub listen: Vec<Listener>,
+    // the served files, the server's own pages in static/ included
+    pub root: String,
+    // other directories under their own url prefixes
+    pub mounts: Vec<Mount>,
+    // users, tokens, TOTP secrets and the ACL
+    pub private_dir: String,
+    // PEM files, https is served on port when both are set
+    pub tls_cert: String,
+    pub tls_key: String,
+    // PEM CA certificates client certificates are checked against
+    pub tls_client_ca: String,
+    // plain http port answering with a redirect to https, 0 is off
+    pub https_redirect_port: usize,
+    // accept .htpasswd entries that aren't hashed, only meant for migrating
+    pub plaintext_passwords: bool,
+    // protection space sent in the basic and digest challenges
+    pub realm: String,
+    // http:// endpoint asked about every request with auth forward
+    pub forward_auth_url: String,
+    // response header of the gateway naming the user
+    pub forward_auth_user_header: String,
     pub limits: ServerLimits,
+    // settings by Host header, this one's with what they change applied
+    pub vhosts: BTreeMap<String, Arc<ServerConfig>>,
+    // name of the virtual host these settings are for, empty for the default
+    pub vhost: String,
 }
 
 impl ServerConfig {
     pub fn new() -> Self {
         ServerConfig {
-            auth_scheme: AuthScheme::None,
+            auth_schemes: Vec::new(),
+            io_mode: IoMode::Threads,
             thread_count: 1,
+            max_threads: 0,
+            thread_stack_size: 0,
+            bulk_threads: 1,
+            bulk_max_threads: 0,
+            bulk_threshold: 1024 * 1024,
             port: 8080,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen: Vec::new(),
+            root: String::from("./public"),
+            mounts: Vec::new(),
+            private_dir: String::from("./private"),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_client_ca: String::new(),
+            https_redirect_port: 0,
+            plaintext_passwords: false,
+            realm: String::from("hello_server"),
+            forward_auth_url: String::new(),
+            forward_auth_user_header: String::from("Remote-User"),
             limits: ServerLimits { 
                 buf_string_limit: 0,
                 file_buf_size_limit: 0,
                 file_size_limit: 0,
             },
+            vhosts: BTreeMap::new(),
+            vhost: String::new(),
         }
     }
-    #[allow(dead_code)]
-    pub fn from_args(mut args: Args) -> Self {
-        let mut s_conf = Self::new();
-        while let Some(arg) = args.next() {
-            match arg.as_str() { 
-                "--auth" | "-a" => {
-                    if let Some(value) = args.next() {
-                        s_conf.auth_scheme = AuthScheme::try_from(value.as_str()).unwrap();
-                    } else {
-                        s_conf.auth_scheme = AuthScheme::Basic;
-                    }
-                },
-                "-t" | "--threads" => {
-                    if let Some(value) = args.next() {
-                        s_conf.thread_count = value.parse().unwrap();
-                    }   
-                },
-                "-p" | "--ports" => {
-                    if let Some(value) = args.next() {
-                        s_conf.port = value.parse().unwrap();
-                    }
-                },                
-                _ => {}
-            }
-        };
-        s_conf
+    /// ./private/config.toml and the environment, see `config::resolve`.
+    pub fn from_config_file() -> Result<Self, String> {
+        crate::config::resolve(&Default::default(), std::env::vars_os()).map(|(s_conf, _)| s_conf)
     }
-    pub fn from_config_file() -> Self {
-        fn size_str_to_bytes_number(str: &str, lines_count: &usize) -> Result<usize, String> {
-            let last_index = str.len()-1;
-            let multiplier = match &str[last_index..last_index+1] {
-                "k" => {
-                    1024
-                },
-                "m" => {
-                    1024 * 1024
-                },
-                "g" => {
-                    1024 * 1024 * 1024
-                },
-                other => {
-                    return Err(format!("unexpected letter '{}' at the end of a number in line {}", other, lines_count))
-                }                                                        
-            };
-            let value = str[..last_index].parse::<usize>().unwrap() * multiplier;
-            Ok(value.to_owned())
-        }        
-        let mut s_conf = Self::new();
-        let file_path = "./private/.config";
-        let file_result = fs::read_to_string(file_path);
-        let file_str = if let Ok(file_str) = file_result {
-            file_str
-        } else {
-            return s_conf;
-        };
-        let mut lines_count = 0;
-        for line in file_str.lines() {
-            lines_count += 1;
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
-            // omit comment
-            if line.len() > 1 && &line[0..2] == "//" {
-                continue;
-            }
-            let mut line_split = line.split('=');
-            let name = if let Some(val) = line_split.next() {
-                val.trim().to_lowercase()
-            } else {
-                panic!("no name for parameter in line {}", lines_count)
-            };
-            if name.is_empty() {
-                panic!("name len == 0 in line {}", lines_count)
-            }                
-            let value = if let Some(val) = line_split.next() {
-                val.trim().to_lowercase()
-            } else {
-                panic!("no value for parameter {} in line {}", name, lines_count)
-            };
-            if value.is_empty() {
-                panic!("value len == 0 in line {}", lines_count)
-            }                
-            match name.as_str() {
-                "auth" => {
-                    s_conf.auth_scheme = AuthScheme::try_from(value.as_str()).unwrap()
-                },
-                "threads" => {
-                    s_conf.thread_count = value.parse().unwrap();
-                }, 
-                "port" => {
-                    s_conf.port = value.parse().unwrap();
-                },
-                "buf_string_limit" => {
-                    s_conf.limits.buf_string_limit = size_str_to_bytes_number(&value, &lines_count).unwrap();
-                },   
-                "file_buf_size_limit" => {
-                    s_conf.limits.file_buf_size_limit = size_str_to_bytes_number(&value, &lines_count).unwrap();
-                },
-                "file_size_limit" => {
-                    s_conf.limits.file_size_limit = size_str_to_bytes_number(&value, &lines_count).unwrap();
-                },                                                                        
-                other => {
-                    panic!("wrong parameter name: '{}' in line {}", other, lines_count);
-                }
-            }
+
+    /// What `listen` names, or the one listener on bind and port.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.listen.is_empty() {
+            return vec![Listener::new(SocketAddr::new(self.bind, self.port as u16))];
         }
-        s_conf
-    }    
-    
-    pub fn update_from_args(&mut self, mut args: Args) {
-        while let Some(arg) = args.next() {
-            match arg.as_str() { 
-                "--auth" | "-a" => {
-                    if let Some(value) = args.next() {
-                        self.auth_scheme = AuthScheme::try_from(value.as_str()).unwrap();
-                    } else {
-                        self.auth_scheme = AuthScheme::Basic;
-                    }
-                },
-                "-t" | "--threads" => {
-                    if let Some(value) = args.next() {
-                        self.thread_count = value.parse().unwrap();
-                    }   
-                },
-                "-p" | "--ports" => {
-                    if let Some(value) = args.next() {
-                        self.port = value.parse().unwrap();
-                    }
-                },                
-                _ => {}
-            }
-        };
-    }    
-    
-    pub fn auth_scheme(&self) -> &AuthScheme {
-        &self.auth_scheme
+        self.listen.clone()
+    }
+    /// The mount `path` is in, the innermost one when they nest.
+    pub fn mount_for(&self, path: &str) -> Option<&Mount> {
+        self.mounts.iter()
+            .filter(|mount| crate::acl::is_below(path, &mount.prefix))
+            .max_by_key(|mount| mount.prefix.len())
+    }
+    pub fn auth_schemes(&self) -> &[AuthScheme] {
+        &self.auth_schemes
     }
     pub fn limits(&self) -> &ServerLimits {
         &self.limits
@@ -312,7 +393,7 @@ mod test {
     }
     #[test]
     fn config_from_file() {
-        let s_conf = ServerConfig::from_config_file();
+        let s_conf = ServerConfig::from_config_file().unwrap();
         println!("{:#?}", s_conf)
     }
 }
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    http::{Request, RequestMethod, Response},
+    server::{self, AuthError},
+};
+
+const COOKIE_NAME: &str = "hello_session";
+// a forgotten browser on a shared machine is logged out after this
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const MAX_AGE: Duration = Duration::from_secs(12 * 3600);
+// between the password and the code of a two-factor login
+const PENDING_COOKIE_NAME: &str = "hello_login";
+const PENDING_LIFETIME: Duration = Duration::from_secs(5 * 60);
+// paths served without a session, the login page has to load
+const PUBLIC_PATHS: [&str; 5] = ["/login", "/login/totp", "/logout", "/static/style.css", "/favicon.ico"];
+// the forms posting there
+const LOGIN_PATHS: [&str; 2] = ["/login", "/login/totp"];
+
+static SESSIONS: OnceLock<Mutex<HashMap<String, Session>>> = OnceLock::new();
+// logins waiting for their TOTP code by id
+static PENDING: OnceLock<Mutex<HashMap<String, Pending>>> = OnceLock::new();
+
+struct Pending {
+    user: String,
+    vhost: String,
+    // when the password was checked
+    created: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user: String,
+    // sent back with uploads and deletes, a cross-site form can't know it
+    pub csrf_token: String,
+    // the virtual host logged in to, its users aren't those of the others
+    vhost: String,
+    created: Instant,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn expired(&self) -> bool {
+        self.created.elapsed() >= MAX_AGE || self.last_seen.elapsed() >= IDLE_TIMEOUT
+    }
+}
+
+/// Whether the request is part of logging in and needs no session, only
+/// reading these pages and sending the login forms.
+pub fn is_public(request: &Request) -> bool {
+    match request.method {
+        RequestMethod::Get => PUBLIC_PATHS.contains(&request.url.as_str()),
+        RequestMethod::Post => LOGIN_PATHS.contains(&request.url.as_str()),
+        _ => false,
+    }
+}
+
+fn sessions() -> std::sync::MutexGuard<'static, HashMap<String, Session>> {
+    SESSIONS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The live session the request's cookie points to, on this virtual host.
+/// Sessions of users no longer in .htpasswd end here too.
+pub fn lookup(request: &Request) -> Result<Session, AuthError> {
+    let id = cookie(request).ok_or_else(|| AuthError::Denied("no session".to_string()))?;
+    let session = {
+        let mut sessions = sessions();
+        let session = match sessions.get_mut(id) {
+            Some(session) if session.vhost != vhost() => return Err(AuthError::Denied("no session".to_string())),
+            Some(session) if !session.expired() => session,
+            Some(_) => {
+                sessions.remove(id);
+                return Err(AuthError::Denied("session expired".to_string()));
+            },
+            None => return Err(AuthError::Denied("session expired".to_string())),
+        };
+        session.last_seen = Instant::now();
+        session.clone()
+    };
+    let users = crate::user_store(crate::users::HTPASSWD_FILE);
+    if users.password(&session.user).map_err(AuthError::Internal)?.is_none() {
+        sessions().remove(id);
+        return Err(AuthError::Denied("user not found".to_string()));
+    }
+    Ok(session)
+}
+
+fn vhost() -> String {
+    crate::s_conf().map(|s_conf| s_conf.vhost.clone()).unwrap_or_default()
+}
+
+pub fn cookie(request: &Request) -> Option<&str> {
+    cookie_named(request, COOKIE_NAME)
+}
+
+fn cookie_named<'a>(request: &'a Request, cookie_name: &str) -> Option<&'a str> {
+    request.headers.get("Cookie")?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| value)
+}
+
+fn set_cookie(request: &Request, name: &str, value: &str, max_age: u64) -> String {
+    let secure = request.peer.tls;
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        name, value, max_age, if secure { "; Secure" } else { "" }
+    )
+}
+
+pub fn redirect(location: &str) -> Response {
+    let mut response = Response::new();
+    response.status = 303;
+    response.headers.insert("Location".to_owned(), location.to_owned());
+    response.headers.insert("Content-Length".to_owned(), "0".to_owned());
+    response
+}
+
+/// `POST /login` with the form from login.html. Users with TOTP go on to
+/// the code form.
+pub fn login(request: &Request) -> Response {
+    let body = String::from_utf8_lossy(&request.body);
+    let username = form_value(&body, "username").unwrap_or_default();
+    let password = form_value(&body, "password").unwrap_or_default();
+    let result = crate::lockout::attempt(request.peer.addr, &username, || server::check_password(&username, &password));
+    match result {
+        Ok(()) => {},
+        Err(AuthError::Internal(e)) => {
+            println!("ERROR: login\n{}", e);
+            return crate::internal_error();
+        },
+        Err(_) => return login_failed(crate::lockout::FAILED_MSG),
+    }
+    match crate::totp::enrolled(&username) {
+        Ok(false) => start(request, username),
+        Ok(true) => {
+            let id = crate::passwd::random_hex(32);
+            {
+                let mut pending = PENDING.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+                pending.retain(|_, pending| pending.created.elapsed() < PENDING_LIFETIME);
+                pending.insert(id.clone(), Pending { user: username, vhost: vhost(), created: Instant::now() });
+            }
+            let mut response = redirect("/login/totp");
+            response.headers.insert("Set-Cookie".to_owned(), set_cookie(request, PENDING_COOKIE_NAME, &id, PENDING_LIFETIME.as_secs()));
+            response
+        },
+        Err(e) => {
+            println!("ERROR: totp\n{}", e);
+            crate::internal_error()
+        },
+    }
+}
+
+/// `POST /login/totp` with the code form from totp.html, the second step.
+pub fn login_totp(request: &Request) -> Response {
+    let id = cookie_named(request, PENDING_COOKIE_NAME).unwrap_or_default();
+    let username = {
+        let pending = PENDING.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+        match pending.get(id) {
+            Some(pending) if pending.vhost == vhost() && pending.created.elapsed() < PENDING_LIFETIME => pending.user.clone(),
+            // password again
+            _ => return redirect("/login"),
+        }
+    };
+    let body = String::from_utf8_lossy(&request.body);
+    let code = form_value(&body, "code").unwrap_or_default();
+    let result = crate::lockout::attempt(request.peer.addr, &username, || match crate::totp::verify(&username, &code) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(AuthError::Denied("wrong code".to_string())),
+        Err(e) => Err(AuthError::Internal(e)),
+    });
+    match result {
+        Ok(()) => {},
+        Err(AuthError::Internal(e)) => {
+            println!("ERROR: login\n{}", e);
+            return crate::internal_error();
+        },
+        Err(_) => return login_failed("wrong code"),
+    }
+    PENDING.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner).remove(id);
+    start(request, username)
+}
+
+fn login_failed(msg: &str) -> Response {
+    let mut response = Response::from(&Request::get_msg_str("login failed", msg)[..]);
+    response.status = 401;
+    response
+}
+
+/// Creates the session of a user who logged in and sends the browser home.
+fn start(request: &Request, username: String) -> Response {
+    let id = crate::passwd::random_hex(32);
+    let now = Instant::now();
+    let session = Session {
+        user: username,
+        csrf_token: crate::passwd::random_hex(16),
+        vhost: vhost(),
+        created: now,
+        last_seen: now,
+    };
+    {
+        let mut sessions = sessions();
+        sessions.retain(|_, session| !session.expired());
+        sessions.insert(id.clone(), session);
+    }
+    let mut response = redirect("/");
+    response.headers.insert("Set-Cookie".to_owned(), set_cookie(request, COOKIE_NAME, &id, MAX_AGE.as_secs()));
+    response
+}
+
+/// `/logout` ends the session on the server and in the browser.
+pub fn logout(request: &Request) -> Response {
+    if let Some(id) = cookie(request) {
+        sessions().remove(id);
+    }
+    let mut response = redirect("/login");
+    response.headers.insert("Set-Cookie".to_owned(), set_cookie(request, COOKIE_NAME, "", 0));
+    response
+}
+
+/// Decoded value of `name` in an `application/x-www-form-urlencoded` body.
+fn form_value(body: &str, name: &str) -> Option<String> {
+    let (_, value) = body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)?;
+    let value = value.replace('+', " ");
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'%', [high, low, tail @ ..]) => {
+                let hex = std::str::from_utf8(&[*high, *low]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(decoded) => {
+                        bytes.push(decoded);
+                        rest = tail;
+                    },
+                    None => {
+                        bytes.push(byte);
+                        rest = &rest[1..];
+                    },
+                }
+            },
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            },
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::form_value;
+
+    #[test]
+    fn form_values() {
+        let body = "username=bob&password=p%40ss+w%3Ard%&x=1";
+        assert_eq!(form_value(body, "username").as_deref(), Some("bob"));
+        assert_eq!(form_value(body, "password").as_deref(), Some("p@ss w:rd%"));
+        assert_eq!(form_value(body, "missing"), None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    fmt::Debug,
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore, ServerConnection, StreamOwned,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::http::{Peer, Transport};
+
+// how often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server side of a TLS connection. The handshake runs on first read.
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl TlsStream {
+    pub fn accept(config: &Arc<rustls::ServerConfig>, stream: TcpStream) -> Result<Self, String> {
+        let connection = ServerConnection::new(config.clone()).map_err(|e| e.to_string())?;
+        Ok(TlsStream(StreamOwned::new(connection, stream)))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn peer(&self) -> Peer {
+        let cert_name = self.0.conn.peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| common_name(cert));
+        Peer {
+            addr: self.0.sock.peer_addr().ok().map(|addr| addr.ip()),
+            cert_name,
+            tls: true,
+            admin: false,
+        }
+    }
+}
+
+fn common_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(str::to_owned)
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // responses without Content-Length end with the connection, tell the
+        // client that's intended and not a truncation
+        self.0.conn.send_close_notify();
+        let _ = self.0.conn.complete_io(&mut self.0.sock);
+    }
+}
+
+/// rustls config serving the certificate chain and key from the given PEM
+/// files, picking up changes to them without a restart. With `client_ca`
+/// clients may present a certificate signed by it, `require_client_cert`
+/// turns away those that don't.
+pub fn server_config(cert_path: &str, key_path: &str, client_ca: Option<&str>, require_client_cert: bool) -> Result<Arc<rustls::ServerConfig>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let resolver = CertReloader::build(cert_path, key_path, provider.clone())?;
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| format!("{}: \"{}\"", e, ca_path))? {
+                let cert = cert.map_err(|e| format!("{}: \"{}\"", e, ca_path))?;
+                roots.add(cert).map_err(|e| format!("{}: \"{}\"", e, ca_path))?;
+            }
+            let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if !require_client_cert {
+                verifier = verifier.allow_unauthenticated();
+            }
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| format!("{}: \"{}\"", e, ca_path))?)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(builder.with_cert_resolver(Arc::new(resolver))))
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: \"{}\"", e, cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found: \"{}\"", cert_path));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| format!("{}: \"{}\"", e, key_path))?;
+    CertifiedKey::from_der(certs, key, provider).map_err(|e| format!("{}: \"{}\"", e, cert_path))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[derive(Debug)]
+struct CertState {
+    certified_key: Arc<CertifiedKey>,
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    checked: Instant,
+}
+
+/// Reloads the certificate when either file's mtime changes. A broken
+/// replacement is logged and the previous certificate kept until the files
+/// change again.
+#[derive(Debug)]
+struct CertReloader {
+    cert_path: String,
+    key_path: String,
+    provider: Arc<CryptoProvider>,
+    state: Mutex<CertState>,
+}
+
+impl CertReloader {
+    fn build(cert_path: &str, key_path: &str, provider: Arc<CryptoProvider>) -> Result<Self, String> {
+        let modified = (modified(cert_path), modified(key_path));
+        let certified_key = load_certified_key(cert_path, key_path, &provider)?;
+        Ok(CertReloader {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            provider,
+            state: Mutex::new(CertState {
+                certified_key: Arc::new(certified_key),
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+}
+
+impl CertReloader {
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.checked.elapsed() >= RELOAD_CHECK_INTERVAL {
+            state.checked = Instant::now();
+            let modified = (modified(&self.cert_path), modified(&self.key_path));
+            if modified != state.modified {
+                match load_certified_key(&self.cert_path, &self.key_path, &self.provider) {
+                    Ok(certified_key) => {
+                        println!("tls certificate reloaded: \"{}\"", self.cert_path);
+                        state.certified_key = Arc::new(certified_key);
+                    },
+                    Err(e) => println!("ERROR: tls certificate reload\n{}", e),
+                }
+                state.modified = modified;
+            }
+        }
+        state.certified_key.clone()
+    }
+}
+
+impl ResolvesServerCert for CertReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use rcgen::{CertificateParams, DnType, KeyPair};
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::{common_name, server_config, CertReloader, TlsStream};
+    use crate::http::Transport;
+
+    #[test]
+    fn serves_and_keeps_broken_reload() {
+        let dir = std::env::temp_dir().join(format!("hello_server_tls_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem").display().to_string(), dir.join("key.pem").display().to_string());
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_owned()]).unwrap().self_signed(&key_pair).unwrap();
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+
+        let config = server_config(&cert_path, &key_path, None, false).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = TlsStream::accept(&config, listener.accept().unwrap().0).unwrap();
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"hello").unwrap();
+            (request, stream.peer().tls)
+        });
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        client.write_all(b"hi tls").unwrap();
+        let mut response = [0; 5];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"hello");
+        assert_eq!(server.join().unwrap(), (*b"hi tl", true));
+
+        // a broken certificate is tried once, not on every check
+        let reloader = CertReloader::build(&cert_path, &key_path, Arc::new(rustls::crypto::ring::default_provider())).unwrap();
+        let served = reloader.current();
+        fs::write(&cert_path, "not a certificate").unwrap();
+        let modified = super::modified(&cert_path);
+        reloader.state.lock().unwrap().checked = Instant::now() - Duration::from_secs(2);
+        assert!(Arc::ptr_eq(&reloader.current(), &served));
+        assert_eq!(reloader.state.lock().unwrap().modified.0, modified);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn client_cert_common_name() {
+        let mut params = CertificateParams::new(vec!["buildbot".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "buildbot");
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        assert_eq!(common_name(cert.der()).as_deref(), Some("buildbot"));
+    }
+}
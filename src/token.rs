@@ -0,0 +1,259 @@
+use std::{
+    fmt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    acl::{self, Permission},
+    server::{Auth, AuthError},
+    users::UserStore,
+};
+
+const TOKENS_FILE: &str = ".tokens";
+const TOKEN_PREFIX: &str = "hs_";
+
+/// What a token may do on top of what its user may do. No permissions
+/// means all of them, no prefix means anywhere.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenScope {
+    permissions: Vec<Permission>,
+    prefix: Option<String>,
+}
+
+impl TokenScope {
+    pub fn permits(&self, path: &str, permission: Permission) -> bool {
+        if !self.permissions.is_empty() && !self.permissions.contains(&permission) {
+            return false;
+        }
+        match (&self.prefix, acl::normalize(path)) {
+            (None, _) => true,
+            (Some(prefix), Some(path)) => acl::is_below(&path, prefix),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+impl fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permissions: String = self.permissions.iter()
+            .map(|permission| match permission {
+                Permission::Read => 'r',
+                Permission::Write => 'w',
+                Permission::Delete => 'd',
+            })
+            .collect();
+        write!(f, "{}:{}", permissions, self.prefix.as_deref().unwrap_or_default())
+    }
+}
+
+/// One `id:user:sha256(secret):expires:permissions:prefix` line of
+/// ./private/.tokens, the part after the id. `expires` is a unix time, 0
+/// for never.
+#[derive(Debug)]
+struct StoredToken {
+    user: String,
+    hash: String,
+    expires: u64,
+    scope: TokenScope,
+}
+
+impl StoredToken {
+    fn parse(stored: &str) -> Option<Self> {
+        let mut fields = stored.splitn(5, ':');
+        let user = fields.next()?.to_owned();
+        let hash = fields.next()?.to_owned();
+        let expires = fields.next()?.parse().ok()?;
+        let permissions = parse_permissions(fields.next()?).ok()?;
+        let prefix = fields.next().filter(|prefix| !prefix.is_empty()).map(str::to_owned);
+        Some(StoredToken { user, hash, expires, scope: TokenScope { permissions, prefix } })
+    }
+}
+
+fn parse_permissions(str: &str) -> Result<Vec<Permission>, String> {
+    str.chars()
+        .map(|char| match char {
+            'r' => Ok(Permission::Read),
+            'w' => Ok(Permission::Write),
+            'd' => Ok(Permission::Delete),
+            other => Err(format!("unknown permission '{}', use r, w and d", other)),
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+fn sha256_hex(str: &str) -> String {
+    format!("{:x}", Sha256::digest(str.as_bytes()))
+}
+
+/// `Authorization: Bearer hs_<id>_<secret>`. The id finds the token, only
+/// the hash of the secret is stored.
+#[derive(Debug)]
+pub struct BearerAuth {
+    credentials_str: String,
+    username: String,
+    scope: TokenScope,
+}
+
+impl BearerAuth {
+    pub fn new(str: &str) -> Self {
+        BearerAuth {
+            credentials_str: str.to_owned(),
+            username: String::new(),
+            scope: TokenScope::default(),
+        }
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    pub fn scope(&self) -> &TokenScope {
+        &self.scope
+    }
+}
+
+impl Auth for BearerAuth {
+    fn authorize(&mut self) -> Result<(), AuthError> {
+        let invalid = || AuthError::Denied("invalid token".to_string());
+        let token = self.credentials_str.strip_prefix("Bearer ").ok_or_else(invalid)?.trim();
+        let (id, secret) = token.strip_prefix(TOKEN_PREFIX)
+            .and_then(|token| token.split_once('_'))
+            .ok_or_else(invalid)?;
+        // no tokens file just means no tokens were created yet
+        if !Path::new(&crate::private_path(TOKENS_FILE)).exists() {
+            return Err(invalid());
+        }
+        let stored = crate::user_store(TOKENS_FILE).password(id).map_err(AuthError::Internal)?.ok_or_else(invalid)?;
+        let stored = StoredToken::parse(&stored)
+            .ok_or_else(|| AuthError::Internal(format!("invalid token line '{}' in {}", id, crate::private_path(TOKENS_FILE))))?;
+        if !crate::passwd::consteq(&sha256_hex(secret), &stored.hash) {
+            return Err(invalid());
+        }
+        if stored.expires != 0 && stored.expires <= now() {
+            return Err(AuthError::Denied("token expired".to_string()));
+        }
+        self.username = stored.user;
+        self.scope = stored.scope;
+        Ok(())
+    }
+}
+
+/// Drops the tokens of a removed user.
+pub fn revoke_user(username: &str) -> Result<usize, String> {
+    if !Path::new(&crate::private_path(TOKENS_FILE)).exists() {
+        return Ok(0);
+    }
+    UserStore::new(&crate::private_path(TOKENS_FILE)).remove_matching(|stored| {
+        StoredToken::parse(stored).is_some_and(|token| token.user == username)
+    })
+}
+
+/// `hello_server token create <USER> [--scope <rwd>] [--prefix <PATH>]
+/// [--expires <N>d|<N>h|never]`, `token revoke <ID>`, `token list`
+///
+/// The token is printed once on creation, ./private/.tokens only keeps its
+/// hash. Tokens expire after 90 days unless told otherwise.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let store = UserStore::new(&crate::private_path(TOKENS_FILE));
+    let command = args.next().ok_or("token needs a command: create, revoke or list")?;
+    match command.as_str() {
+        "create" => {
+            let user = args.next().ok_or("token create needs a username")?;
+            if crate::user_store(crate::users::HTPASSWD_FILE).password(&user)?.is_none() {
+                return Err(format!("user '{}' not found", user));
+            }
+            let mut scope = TokenScope::default();
+            let mut lifetime = Some(90 * 24 * 3600);
+            while let Some(arg) = args.next() {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                match arg.as_str() {
+                    "--scope" => scope.permissions = parse_permissions(&value)?,
+                    "--prefix" => {
+                        let prefix = acl::normalize(&value)
+                            .filter(|_| value.starts_with('/'))
+                            .ok_or_else(|| format!("invalid prefix '{}'", value))?;
+                        scope.prefix = Some(prefix);
+                    },
+                    "--expires" => lifetime = parse_lifetime(&value)?,
+                    other => return Err(format!("unknown token option '{}'", other)),
+                }
+            }
+            let id = crate::passwd::random_hex(8);
+            let secret = crate::passwd::random_hex(24);
+            let expires = match lifetime {
+                Some(lifetime) => now().checked_add(lifetime).ok_or("token expiry out of range")?,
+                None => 0,
+            };
+            store.set(&id, &format!("{}:{}:{}:{}", user, sha256_hex(&secret), expires, scope))?;
+            println!("{}{}_{}", TOKEN_PREFIX, id, secret);
+        },
+        "revoke" => {
+            let id = args.next().ok_or("token revoke needs a token id")?;
+            let id = id.strip_prefix(TOKEN_PREFIX).map_or(id.as_str(), |token| token.split('_').next().unwrap_or_default());
+            store.remove(id)?;
+            println!("token {} revoked", id);
+        },
+        "list" => {
+            for id in store.usernames()? {
+                let token = match store.password(&id)?.as_deref().and_then(StoredToken::parse) {
+                    Some(val) => val,
+                    None => continue,
+                };
+                let expires = match token.expires {
+                    0 => "never expires".to_string(),
+                    expires if expires <= now() => "expired".to_string(),
+                    expires => format!("expires in {}h", (expires - now()) / 3600),
+                };
+                let scope = if token.scope == TokenScope::default() { "unscoped".to_string() } else { token.scope.to_string() };
+                println!("{} {} {} {}", id, token.user, scope, expires);
+            }
+        },
+        other => return Err(format!("unknown token command '{}'", other)),
+    }
+    Ok(())
+}
+
+/// Seconds from `30d` or `12h`, None for `never`.
+fn parse_lifetime(str: &str) -> Result<Option<u64>, String> {
+    if str == "never" {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid expiry '{}', use e.g. 30d, 12h or never", str);
+    let (number, unit) = str.split_at(str.len().saturating_sub(1));
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let lifetime = match unit {
+        "d" => number.checked_mul(24 * 3600),
+        "h" => number.checked_mul(3600),
+        _ => None,
+    };
+    // the expiry stored is now() + lifetime, which has to fit as well
+    match lifetime.filter(|lifetime| now().checked_add(*lifetime).is_some()) {
+        Some(lifetime) => Ok(Some(lifetime)),
+        None => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_lifetime, Permission, StoredToken};
+
+    #[test]
+    fn stored_token_scope() {
+        let token = StoredToken::parse("releasebot:abc:0:rw:/content/releases").unwrap();
+        assert_eq!(token.user, "releasebot");
+        assert!(token.scope.permits("/content/releases/v2.tar.gz", Permission::Write));
+        assert!(!token.scope.permits("/content/releases/v1.tar.gz", Permission::Delete));
+        assert!(!token.scope.permits("/content/upload", Permission::Read));
+        let token = StoredToken::parse("alice:abc:0::").unwrap();
+        assert!(token.scope.permits("/anything", Permission::Delete));
+        assert_eq!(token.scope.to_string(), ":");
+        assert_eq!(parse_lifetime("2d"), Ok(Some(2 * 24 * 3600)));
+        assert!(parse_lifetime("2w").is_err());
+        assert!(parse_lifetime(&format!("{}d", u64::MAX / 3600)).is_err());
+        assert!(parse_lifetime(&format!("{}h", u64::MAX / 3600)).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Mutex, OnceLock, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+use crate::{server::AuthError, users::UserStore};
+
+pub const TOTP_FILE: &str = ".totp";
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// (.totp path, username) -> last time step a code was accepted for, so a code
+// works once; the path tells the users of different private_dirs apart
+static LAST_STEPS: OnceLock<Mutex<HashMap<(String, String), u64>>> = OnceLock::new();
+
+/// One `username:secret:recovery,hashes` line of .totp in the private_dir, the part
+/// after the username. The secret is base32, the recovery codes are kept as
+/// SHA-256 hashes and crossed off when used.
+struct Enrollment {
+    secret: Vec<u8>,
+    recovery_hashes: Vec<String>,
+}
+
+impl Enrollment {
+    fn parse(stored: &str) -> Option<Self> {
+        let (secret, recovery) = stored.split_once(':')?;
+        Some(Enrollment {
+            secret: base32_decode(secret)?,
+            recovery_hashes: recovery.split(',').filter(|hash| !hash.is_empty()).map(str::to_owned).collect(),
+        })
+    }
+    fn to_stored(&self) -> String {
+        format!("{}:{}", base32_encode(&self.secret), self.recovery_hashes.join(","))
+    }
+}
+
+fn stored(username: &str) -> Result<Option<String>, String> {
+    // no file just means nobody enrolled yet
+    if !Path::new(&crate::private_path(TOTP_FILE)).exists() {
+        return Ok(None);
+    }
+    crate::user_store(TOTP_FILE).password(username)
+}
+
+/// Whether `username` needs a code after the password.
+pub fn enrolled(username: &str) -> Result<bool, String> {
+    Ok(stored(username)?.is_some())
+}
+
+/// For logins that can't ask for a code, basic and digest auth. Enrolled
+/// users use the login page, scripts a token.
+pub fn refuse_enrolled(username: &str) -> Result<(), AuthError> {
+    match enrolled(username) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(AuthError::Denied("two-factor login required, log in at /login".to_string())),
+        Err(e) => Err(AuthError::Internal(e)),
+    }
+}
+
+/// Checks a 6 digit code from the authenticator app, or one of the recovery
+/// codes, which is used up by that.
+pub fn verify(username: &str, code: &str) -> Result<bool, String> {
+    let stored = match stored(username)? {
+        Some(val) => val,
+        None => return Ok(false),
+    };
+    let invalid = || format!("invalid line '{}' in {}", username, crate::private_path(TOTP_FILE));
+    let enrollment = Enrollment::parse(&stored).ok_or_else(invalid)?;
+    let code: String = code.chars().filter(|char| !char.is_whitespace() && *char != '-').collect();
+    if code.len() == DIGITS as usize && code.chars().all(|char| char.is_ascii_digit()) {
+        let account = (crate::private_path(TOTP_FILE), username.to_owned());
+        return Ok(verify_code(&enrollment.secret, account, &code, now()));
+    }
+    let hash = sha256_hex(&code.to_lowercase());
+    // looked up again under the lock, two logins mustn't both use a code
+    let mut left = None;
+    UserStore::new(&crate::private_path(TOTP_FILE)).update(username, |stored| {
+        let mut enrollment = Enrollment::parse(stored).ok_or_else(invalid)?;
+        let index = match enrollment.recovery_hashes.iter().position(|stored| crate::passwd::consteq(stored, &hash)) {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+        enrollment.recovery_hashes.remove(index);
+        left = Some(enrollment.recovery_hashes.len());
+        Ok(Some(enrollment.to_stored()))
+    })?;
+    match left {
+        Some(left) => {
+            println!("recovery code of '{}' used, {} left", username, left);
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+/// Accepts the codes of the current step and one either side, for clocks a
+/// little off, but none at or before a step that already logged in.
+fn verify_code(secret: &[u8], account: (String, String), code: &str, time: u64) -> bool {
+    let step = time / STEP;
+    let mut last_steps = LAST_STEPS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    let last_step = last_steps.get(&account).copied();
+    for candidate in [step.saturating_sub(1), step, step + 1] {
+        if last_step.is_some_and(|last_step| candidate <= last_step) {
+            continue;
+        }
+        if crate::passwd::consteq(&hotp(secret, candidate), code) {
+            last_steps.insert(account, candidate);
+            return true;
+        }
+    }
+    false
+}
+
+/// RFC 4226 HOTP with HMAC-SHA1, what authenticator apps expect by default.
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let tag = tag.as_ref();
+    let offset = (tag[tag.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([tag[offset], tag[offset + 1], tag[offset + 2], tag[offset + 3]]) & 0x7fff_ffff;
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+fn sha256_hex(str: &str) -> String {
+    format!("{:x}", Sha256::digest(str.as_bytes()))
+}
+
+/// For the label and issuer of the otpauth URI, everything but the unreserved
+/// characters of RFC 3986 percent-encoded.
+fn uri_encode(str: &str) -> String {
+    str.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut bytes = [0u8; 8];
+        bytes[3..3 + chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes(bytes);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for index in 0..chars {
+            let value = (bits >> (35 - index * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[value as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(str: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits: u64 = 0;
+    let mut bit_count = 0;
+    for char in str.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET.iter().position(|&letter| letter as char == char.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// `hello_server user totp <NAME>` enrolls the user, or enrolls them anew,
+/// printing the provisioning URI for the authenticator app and the recovery
+/// codes. Nothing of it can be shown again. `--remove` turns TOTP off.
+pub fn run(username: &str, remove: bool) -> Result<(), String> {
+    let store = UserStore::new(&crate::private_path(TOTP_FILE));
+    if remove {
+        store.remove(username)?;
+        println!("two-factor login of '{}' turned off", username);
+        return Ok(());
+    }
+    let secret = crate::passwd::random_bytes(20);
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = crate::passwd::random_hex(5);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+    let enrollment = Enrollment {
+        secret,
+        recovery_hashes: recovery_codes.iter().map(|code| sha256_hex(&code.replace('-', ""))).collect(),
+    };
+    store.set(username, &enrollment.to_stored())?;
+    let issuer = uri_encode(&crate::s_conf().ok_or("S_CONF uninitialized")?.realm);
+    println!("add this to the authenticator app, e.g. as a QR code:");
+    println!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, uri_encode(username), base32_encode(&enrollment.secret), issuer, DIGITS, STEP
+    );
+    println!("recovery codes, each works once in place of a code:");
+    for code in recovery_codes {
+        println!("  {}", code);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{base32_decode, base32_encode, hotp, sha256_hex, uri_encode, verify, verify_code, STEP};
+    use crate::server::ServerConfig;
+
+    #[test]
+    fn rfc6238_sha1() {
+        let secret = b"12345678901234567890";
+        assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").as_deref(), Some(&secret[..]));
+        // appendix B, the last 6 of the 8 digits
+        assert_eq!(hotp(secret, 59 / STEP), "287082");
+        assert_eq!(hotp(secret, 1111111109 / STEP), "081804");
+        let account = |private_dir: &str| (format!("{}/.totp", private_dir), "totp_test".to_owned());
+        assert!(verify_code(secret, account("a"), "081804", 1111111109 + STEP));
+        // once only, per private_dir
+        assert!(!verify_code(secret, account("a"), "081804", 1111111109));
+        assert!(verify_code(secret, account("b"), "081804", 1111111109));
+    }
+
+    #[test]
+    fn otpauth_label() {
+        assert_eq!(uri_encode("My Files: a?b"), "My%20Files%3A%20a%3Fb");
+        assert_eq!(uri_encode("alice.b-c_d~"), "alice.b-c_d~");
+    }
+
+    #[test]
+    fn recovery_code_once() {
+        let private_dir = std::env::temp_dir().join(format!("hello_server_totp_{}", std::process::id()));
+        std::fs::create_dir_all(&private_dir).unwrap();
+        let line = format!("alice:{}:{},{}\n", base32_encode(b"12345678901234567890"), sha256_hex("abcde12345"), sha256_hex("fghij67890"));
+        std::fs::write(private_dir.join(".totp"), line).unwrap();
+        let mut s_conf = ServerConfig::new();
+        s_conf.private_dir = private_dir.display().to_string();
+        let previous = crate::VHOST_CONF.with(|current| current.replace(Some(Arc::new(s_conf))));
+
+        assert_eq!(verify("alice", "abcde-12345"), Ok(true));
+        assert_eq!(verify("alice", "abcde-12345"), Ok(false));
+        assert_eq!(verify("alice", "FGHIJ-67890"), Ok(true));
+
+        crate::VHOST_CONF.with(|current| current.replace(previous));
+        std::fs::remove_dir_all(&private_dir).unwrap();
+    }
+}
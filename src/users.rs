@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead},
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+pub const HTPASSWD_FILE: &str = ".htpasswd";
+pub const HTDIGEST_FILE: &str = ".htdigest";
+
+#[derive(Debug, Default)]
+struct UsersState {
+    // what was parsed; mtime and size miss a new hash of the same length
+    // written within the mtime granularity
+    content: Option<String>,
+    passwords: HashMap<String, String>,
+}
+
+/// Users and password hashes from an `.htpasswd` file. The file is parsed
+/// again whenever its content changes, so removed users and changed
+/// passwords take effect with the next request.
+#[derive(Debug)]
+pub struct UserStore {
+    path: String,
+    state: Mutex<UsersState>,
+}
+
+impl UserStore {
+    pub fn new(path: &str) -> Self {
+        UserStore {
+            path: path.to_owned(),
+            state: Mutex::new(UsersState::default()),
+        }
+    }
+
+    /// Stored password of `username`, `Ok(None)` for an unknown user and an
+    /// error when the file can't be read.
+    pub fn password(&self, username: &str) -> Result<Option<String>, String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let htpasswd = match fs::read_to_string(&self.path) {
+            Ok(val) => val,
+            Err(e) => {
+                // nobody gets in on stale data
+                *state = UsersState::default();
+                return Err(format!("{}: \"{}\"", e, self.path));
+            }
+        };
+        if state.content.as_deref() != Some(htpasswd.as_str()) {
+            state.passwords = parse(&htpasswd);
+            state.content = Some(htpasswd);
+        }
+        Ok(state.passwords.get(username).cloned())
+    }
+
+    pub fn usernames(&self) -> Result<Vec<String>, String> {
+        let htpasswd = fs::read_to_string(&self.path).map_err(|e| format!("{}: \"{}\"", e, self.path))?;
+        let mut usernames: Vec<String> = parse(&htpasswd).into_keys().collect();
+        usernames.sort();
+        Ok(usernames)
+    }
+
+    pub fn add(&self, username: &str, password: &str) -> Result<(), String> {
+        validate_username(username)?;
+        let line = format!("{}:{}", username, crate::passwd::hash(password)?);
+        self.edit(|lines| {
+            if lines.iter().any(|other| line_username(other) == Some(username)) {
+                return Err(format!("user '{}' exists", username));
+            }
+            lines.push(line);
+            Ok(())
+        })
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<(), String> {
+        let hash = crate::passwd::hash(password)?;
+        self.edit(|lines| {
+            let line = lines.iter_mut()
+                .find(|line| line_username(line) == Some(username))
+                .ok_or_else(|| format!("user '{}' not found", username))?;
+            *line = format!("{}:{}", username, hash);
+            Ok(())
+        })
+    }
+
+    /// Adds the user or replaces what's stored for them, as is.
+    pub fn set(&self, username: &str, value: &str) -> Result<(), String> {
+        validate_username(username)?;
+        let new_line = format!("{}:{}", username, value);
+        self.edit(|lines| {
+            match lines.iter_mut().find(|line| line_username(line) == Some(username)) {
+                Some(line) => *line = new_line,
+                None => lines.push(new_line),
+            }
+            Ok(())
+        })
+    }
+
+    /// Replaces what's stored for `username` with what `change` makes of
+    /// it, all under the edit lock; `None` keeps it.
+    pub fn update(&self, username: &str, change: impl FnOnce(&str) -> Result<Option<String>, String>) -> Result<(), String> {
+        self.edit(|lines| {
+            let line = lines.iter_mut()
+                .find(|line| line_username(line) == Some(username))
+                .ok_or_else(|| format!("user '{}' not found", username))?;
+            let stored = line.trim().split_once(':').map_or("", |(_, stored)| stored);
+            if let Some(new_stored) = change(stored)? {
+                *line = format!("{}:{}", username, new_stored);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn remove(&self, username: &str) -> Result<(), String> {
+        self.edit(|lines| {
+            let count = lines.len();
+            lines.retain(|line| line_username(line) != Some(username));
+            if lines.len() == count {
+                return Err(format!("user '{}' not found", username));
+            }
+            Ok(())
+        })
+    }
+
+    /// Removes every entry whose stored value matches, the number removed.
+    pub fn remove_matching(&self, matches: impl Fn(&str) -> bool) -> Result<usize, String> {
+        let mut removed = 0;
+        self.edit(|lines| {
+            let count = lines.len();
+            lines.retain(|line| match line.trim().split_once(':') {
+                Some((_, value)) if line_username(line).is_some() => !matches(value),
+                _ => true,
+            });
+            removed = count - lines.len();
+            Ok(())
+        })?;
+        Ok(removed)
+    }
+
+    /// Changes the file's lines under an exclusive lock on a lock file next
+    /// to it, so concurrent edits don't overwrite each other. The new content
+    /// is written beside the file and renamed over it, the server never reads
+    /// a half written file.
+    fn edit(&self, change: impl FnOnce(&mut Vec<String>) -> Result<(), String>) -> Result<(), String> {
+        let lock_path = format!("{}.lock", self.path);
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| format!("{}: \"{}\"", e, lock_path))?;
+        lock.lock().map_err(|e| format!("{}: \"{}\"", e, lock_path))?;
+        let htpasswd = match fs::read_to_string(&self.path) {
+            Ok(val) => val,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: \"{}\"", e, self.path)),
+        };
+        let mut lines: Vec<String> = htpasswd.lines().map(str::to_owned).collect();
+        change(&mut lines)?;
+        let mut content = lines.join("\n");
+        content.push('\n');
+        if content == htpasswd {
+            return Ok(());
+        }
+        let tmp_path = format!("{}.tmp", self.path);
+        crate::init::write_private(&tmp_path, &content)?;
+        fs::rename(&tmp_path, &self.path).map_err(|e| format!("{}: \"{}\"", e, self.path))
+    }
+}
+
+fn line_username(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    line.split_once(':').map(|(username, _)| username)
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.starts_with('#') || username.contains(|c: char| c == ':' || c.is_whitespace() || c.is_control()) {
+        return Err(format!("invalid username '{}'", username));
+    }
+    Ok(())
+}
+
+/// `hello_server user add|passwd|digest <NAME> [--stdin]`,
+/// `user totp <NAME> [--remove]`, `user remove <NAME>`, `user list`
+///
+/// Edits ./private/.htpasswd, a running server picks the changes up with
+/// the next request. Passwords are prompted for twice without echo, or read
+/// as one line from stdin with `--stdin` for scripts. Once ./private/.htdigest
+/// exists, which `digest` creates, it's kept in step for digest auth.
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<(), String> {
+    let htdigest_path = crate::private_path(HTDIGEST_FILE);
+    let totp_path = crate::private_path(crate::totp::TOTP_FILE);
+    let store = UserStore::new(&crate::private_path(HTPASSWD_FILE));
+    let digest_store = UserStore::new(&htdigest_path);
+    let digest_enabled = Path::new(&htdigest_path).exists();
+    let command = args.next().ok_or("user needs a command: add, passwd, digest, totp, remove or list")?;
+    if command == "list" {
+        for username in store.usernames()? {
+            println!("{}", username);
+        }
+        return Ok(());
+    }
+    let username = args.next().ok_or_else(|| format!("user {} needs a username", command))?;
+    if command == "totp" {
+        let remove = match args.next().as_deref() {
+            Some("--remove") => true,
+            Some(other) => return Err(format!("unknown user option '{}'", other)),
+            None => false,
+        };
+        if !remove && store.password(&username)?.is_none() {
+            return Err(format!("user '{}' not found", username));
+        }
+        return crate::totp::run(&username, remove);
+    }
+    let stdin = match args.next().as_deref() {
+        Some("--stdin") => true,
+        Some(other) => return Err(format!("unknown user option '{}'", other)),
+        None => false,
+    };
+    match command.as_str() {
+        "add" => {
+            let password = read_password(stdin)?;
+            store.add(&username, &password)?;
+            if digest_enabled {
+                set_digest(&digest_store, &username, &password)?;
+            }
+            println!("user '{}' added", username);
+        },
+        "passwd" => {
+            let password = read_password(stdin)?;
+            store.set_password(&username, &password)?;
+            if digest_enabled {
+                set_digest(&digest_store, &username, &password)?;
+            }
+            println!("password of '{}' changed", username);
+        },
+        "digest" => {
+            set_digest(&digest_store, &username, &read_password(stdin)?)?;
+            println!("digest credentials of '{}' set in {}", username, htdigest_path);
+        },
+        "remove" => {
+            let removed = store.remove(&username);
+            let digest_removed = digest_enabled && digest_store.remove(&username).is_ok();
+            if !digest_removed {
+                removed?;
+            }
+            if Path::new(&totp_path).exists() {
+                // not enrolled is fine
+                let _ = UserStore::new(&totp_path).remove(&username);
+            }
+            let tokens = crate::token::revoke_user(&username)?;
+            println!("user '{}' removed", username);
+            if tokens > 0 {
+                println!("{} token(s) of '{}' revoked", tokens, username);
+            }
+        },
+        other => return Err(format!("unknown user command '{}'", other)),
+    }
+    Ok(())
+}
+
+fn set_digest(digest_store: &UserStore, username: &str, password: &str) -> Result<(), String> {
+    let realm = crate::s_conf().ok_or("S_CONF uninitialized")?.realm.clone();
+    digest_store.set(username, &crate::digest::ha1_entry(username, &realm, password))
+}
+
+fn read_password(stdin: bool) -> Result<String, String> {
+    let password = if stdin {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    } else {
+        let password = rpassword::prompt_password("password: ")
+            .map_err(|e| format!("{}, use --stdin without a terminal", e))?;
+        if rpassword::prompt_password("repeat password: ").map_err(|e| e.to_string())? != password {
+            return Err("passwords don't match".to_string());
+        }
+        password
+    };
+    if password.is_empty() {
+        return Err("empty password".to_string());
+    }
+    Ok(password)
+}
+
+fn parse(htpasswd: &str) -> HashMap<String, String> {
+    htpasswd.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(username, password)| (username.to_owned(), password.to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::UserStore;
+
+    #[test]
+    fn exact_username_and_reload() {
+        let path = std::env::temp_dir().join(format!("hello_server_users_{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, "jimbob:secret\n# bob:commented\n").unwrap();
+        let users = UserStore::new(path_str);
+        assert_eq!(users.password("jimbob").unwrap().as_deref(), Some("secret"));
+        assert_eq!(users.password("bob").unwrap(), None);
+
+        fs::write(&path, "bob:other\n").unwrap();
+        assert_eq!(users.password("jimbob").unwrap(), None);
+        assert_eq!(users.password("bob").unwrap().as_deref(), Some("other"));
+        // same size, likely the same mtime
+        fs::write(&path, "bob:newer\n").unwrap();
+        assert_eq!(users.password("bob").unwrap().as_deref(), Some("newer"));
+
+        fs::remove_file(&path).unwrap();
+        assert!(users.password("bob").is_err());
+    }
+
+    #[test]
+    fn edit_users() {
+        let path = std::env::temp_dir().join(format!("hello_server_edit_{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, "# staff\njimbob:secret\n").unwrap();
+        let users = UserStore::new(path_str);
+        users.add("bob", "hunter2").unwrap();
+        assert!(users.add("bob", "again").is_err());
+        assert!(users.add("a:b", "hunter2").is_err());
+        users.set_password("bob", "hunter3").unwrap();
+        assert!(crate::passwd::verify("hunter3", &users.password("bob").unwrap().unwrap(), false));
+        users.remove("jimbob").unwrap();
+        assert!(users.remove("jimbob").is_err());
+        assert_eq!(users.usernames().unwrap(), vec!["bob".to_owned()]);
+        assert!(fs::read_to_string(&path).unwrap().starts_with("# staff\nbob:$2b$"));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(format!("{}.lock", path_str)).unwrap();
+    }
+}